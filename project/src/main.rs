@@ -9,27 +9,48 @@
 extern crate may;
 extern crate may_minihttp;
 
+macro_rules! sql_params {
+    (@acc [$($out:expr,)*]) => { &[$($out,)*] };
+    (@acc [$($out:expr,)*] null $(, $($rest:tt)*)?) => {
+        sql_params!(@acc [$($out,)* $crate::SqlParam::Null,] $($($rest)*)?)
+    };
+    (@acc [$($out:expr,)*] None $(, $($rest:tt)*)?) => {
+        sql_params!(@acc [$($out,)* $crate::SqlParam::Null,] $($($rest)*)?)
+    };
+    (@acc [$($out:expr,)*] $param:expr $(, $($rest:tt)*)?) => {
+        sql_params!(@acc [$($out,)* $crate::SqlParam::from($param),] $($($rest)*)?)
+    };
+    ($($params:tt)*) => { sql_params!(@acc [] $($params)*) };
+}
+
 mod server;
+mod shared;
 
 const CONFIG: &str = include_str!("../config.json");
 
 use may_minihttp::{HttpService, HttpServiceFactory, Request, Response};
-use may_postgres::{types::ToSql, Client, Statement};
+use may_postgres::{types::{to_sql_checked, FromSql, IsNull, Kind, ToSql, Type}, Client, Statement};
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
+use bytes::BytesMut;
 // use smallvec::SmallVec;
 use lazy_static::lazy_static;
+use may::sync::RwLock;
 
 use ::std::{
+    cell::Cell,
     collections::HashMap,
-    fs, io, io::BufRead,
+    sync::Arc,
+    fs, io, io::{BufRead, Read},
+    str::FromStr,
     path::Path,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use rust_embed::RustEmbed;
 use compact_str::{ToCompactString, format_compact, CompactString};
-use regex::Regex;
 
 #[derive(RustEmbed)]
 #[folder = "build/"]
@@ -77,58 +98,1053 @@ struct AppConfig {
     name: String,
     port: u16,
     postgres: PostgresConfig,
+    /// Adds the underlying error to the JSON error responses.
+    #[serde(default)]
+    debug: bool,
+    /// The number of proxies in front of the server, each adding the address
+    /// it got the request from to X-Forwarded-For, see `UbiRequest::ip`.
+    #[serde(default = "one_proxy")]
+    trusted_proxies: usize,
+}
+
+fn one_proxy() -> usize {
+    1
 }
 
 struct Context {
     db: PgConnection,
+    /// The header lines of the response being sent, see `UbiResponse::write`.
+    header_lines: Vec<String>,
+}
+
+/// What a route handler sends back. A plain string (the result of `ubi.json`)
+/// becomes a 200 JSON response, `ubi.response` builds anything else.
+pub struct UbiResponse {
+    status: u16,
+    headers: Vec<String>,
+    body: String,
+    /// A header that can't be sent, answered instead of the response.
+    error: Option<UbiError>,
+}
+
+impl UbiResponse {
+    pub fn new() -> Self {
+        UbiResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: String::new(),
+            error: None,
+        }
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// A line break in a header would let the value add headers of its
+    /// own, such a response is answered with a 500 error.
+    pub fn header(mut self, name: impl ToString, value: impl ToString) -> Self {
+        let line = format!("{}: {}", name.to_string(), value.to_string());
+        if line.contains(['\r', '\n']) {
+            let name = line.split(':').next().unwrap_or_default().to_string();
+            self.error.get_or_insert_with(|| {
+                UbiError::new(500, "invalid_header", "The response has a header with a line break in it")
+                    .details(format!("header `{}`", name.trim()))
+            });
+        } else {
+            self.headers.push(line);
+        }
+        self
+    }
+
+    pub fn cookie(self, name: impl ToString, value: impl ToString) -> Self {
+        let cookie = format!("{}={}; Path=/; HttpOnly", name.to_string(), value.to_string());
+        self.header("set-cookie", cookie)
+    }
+
+    pub fn body(mut self, body: impl ToString) -> Self {
+        self.body = body.to_string();
+        self
+    }
+
+    /// may_minihttp only takes `&'static str` headers. The lines are kept in
+    /// `lines`, owned by the connection, until the next response of the
+    /// connection is written.
+    fn write(mut self, res: &mut Response, lines: &mut Vec<String>) {
+        if let Some(error) = self.error.take() {
+            return UbiResponse::from(error).write(res, lines);
+        }
+        res.status_code(self.status as usize, reason_phrase(self.status));
+        let has_content_type = self
+            .headers
+            .iter()
+            .any(|h| h.to_ascii_lowercase().starts_with("content-type:"));
+        if !has_content_type {
+            res.header("content-type: application/json");
+        }
+        lines.clear();
+        lines.extend(self.headers);
+        for line in lines.iter() {
+            // SAFETY: may_minihttp 0.1.11, which Cargo.toml requires, encodes
+            // the response right after `call` returns and before the next
+            // request of the connection is handed to the same `Context`, the
+            // only one clearing `lines`, and doesn't keep the `&str` after.
+            // The text of a `String` doesn't move when the `Vec` holding it
+            // does.
+            res.header(unsafe { &*(line.as_str() as *const str) });
+        }
+        res.body_vec(self.body.into_bytes());
+    }
+}
+
+impl From<String> for UbiResponse {
+    fn from(body: String) -> Self {
+        UbiResponse::new().body(body)
+    }
+}
+
+impl From<&str> for UbiResponse {
+    fn from(body: &str) -> Self {
+        UbiResponse::new().body(body)
+    }
+}
+
+/// A failure anywhere in the request path. It is sent to the client as
+/// `{"error": {"status", "kind", "message"}}`, plus `details` when `debug` is
+/// enabled in config.json.
+#[derive(Debug)]
+pub struct UbiError {
+    status: u16,
+    kind: &'static str,
+    message: String,
+    details: Option<String>,
+    fields: Vec<FieldError>,
+}
+
+impl UbiError {
+    pub fn new(status: u16, kind: &'static str, message: impl ToString) -> Self {
+        UbiError {
+            status,
+            kind,
+            message: message.to_string(),
+            details: None,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn bad_request(message: impl ToString) -> Self {
+        UbiError::new(400, "bad_request", message)
+    }
+
+    pub fn not_found(message: impl ToString) -> Self {
+        UbiError::new(404, "not_found", message)
+    }
+
+    pub fn details(mut self, details: impl ToString) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+
+    pub fn fields(mut self, fields: Vec<FieldError>) -> Self {
+        self.fields = fields;
+        self
+    }
+}
+
+impl std::fmt::Display for UbiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.kind, self.status, self.message)
+    }
+}
+
+impl From<may_postgres::Error> for UbiError {
+    fn from(e: may_postgres::Error) -> Self {
+        UbiError::new(500, "database_error", "The database query failed").details(e)
+    }
+}
+
+impl From<serde_json::Error> for UbiError {
+    fn from(e: serde_json::Error) -> Self {
+        UbiError::new(400, "invalid_json", "The request body is not valid JSON").details(e)
+    }
+}
+
+impl From<io::Error> for UbiError {
+    fn from(e: io::Error) -> Self {
+        UbiError::new(500, "io_error", "Failed to read the request").details(e)
+    }
+}
+
+impl From<UbiError> for UbiResponse {
+    fn from(e: UbiError) -> Self {
+        let mut error = json!({
+            "status": e.status,
+            "kind": e.kind,
+            "message": e.message,
+        });
+        if !e.fields.is_empty() {
+            error["fields"] = json!(e.fields);
+        }
+        if let (true, Some(details)) = (APP_CONFIG.debug, e.details) {
+            error["details"] = Value::String(details);
+        }
+        UbiResponse::new()
+            .status(e.status)
+            .body(json!({ "error": error }))
+    }
+}
+
+/// The request as route handlers see it, `ubi.req` in server.ts/server.py.
+pub struct UbiRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    cookies: Vec<(String, String)>,
+    params: HashMap<String, String>,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl UbiRequest {
+    fn new(req: Request, params: HashMap<String, String>) -> Result<Self, UbiError> {
+        let (path, query) = match req.path().split_once('?') {
+            Some((path, query)) => (path.to_string(), parse_query(query)),
+            None => (req.path().to_string(), Vec::new()),
+        };
+
+        let headers: Vec<(String, String)> = req
+            .headers()
+            .iter()
+            .map(|h| (h.name.to_ascii_lowercase(), String::from_utf8_lossy(h.value).trim().to_string()))
+            .collect();
+        let cookies = headers
+            .iter()
+            .filter(|(name, _)| name == "cookie")
+            .flat_map(|(_, value)| parse_cookies(value))
+            .collect();
+
+        let method = req.method().to_ascii_uppercase();
+        let mut body = Vec::new();
+        req.body().read_to_end(&mut body)?;
+
+        Ok(UbiRequest { method, path, headers, cookies, params, query, body })
+    }
+
+    /// `ubi.req.method`, in upper case.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// `ubi.req.path`, without the query string.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// `ubi.req.header(name)`, the name is case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// `ubi.req.cookie(name)`
+    pub fn cookie(&self, name: &str) -> Option<&String> {
+        self.cookies.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+
+    /// `ubi.req.ip`. may_minihttp doesn't hand out the peer address, so this is
+    /// the client the proxies in front of the server report, if any.
+    pub fn ip(&self) -> Option<&str> {
+        self.forwarded_ip(APP_CONFIG.trusted_proxies)
+    }
+
+    /// The client of X-Forwarded-For as seen by the outermost of
+    /// `trusted_proxies` proxies, counted from the right: the entries on its
+    /// left come from the client and can be anything it likes. Without a
+    /// proxy every header is the client's own.
+    fn forwarded_ip(&self, trusted_proxies: usize) -> Option<&str> {
+        if trusted_proxies == 0 {
+            return None;
+        }
+        let forwarded: Vec<&str> = self
+            .headers
+            .iter()
+            .filter(|(k, _)| k == "x-forwarded-for")
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .collect();
+        if forwarded.is_empty() {
+            return self.header("x-real-ip").map(|ip| ip.trim()).filter(|ip| !ip.is_empty());
+        }
+        let ip = forwarded.len().checked_sub(trusted_proxies)?;
+        Some(forwarded[ip]).filter(|ip| !ip.is_empty())
+    }
+
+    /// `ubi.req.params(name)`, a `:name` or `*name` segment of the route.
+    pub fn params(&self, name: &str) -> Option<&String> {
+        self.params.get(name)
+    }
+
+    /// `ubi.req.query(name)`, the first `name=` value of the query string.
+    pub fn query(&self, name: &str) -> Option<&String> {
+        self.query.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+
+    /// `ubi.req.queryAll(name)`, every value of a repeated query parameter.
+    pub fn query_all(&self, name: &str) -> Vec<&String> {
+        self.query.iter().filter(|(k, _)| k == name).map(|(_, v)| v).collect()
+    }
+
+    /// `ubi.req.query<T>(name)`, answering 400 when the value doesn't parse.
+    pub fn query_as<T: FromStr>(&self, name: &str) -> Result<Option<T>, UbiError> {
+        self.query(name).map(|v| parse_query_value(name, v)).transpose()
+    }
+
+    /// `ubi.req.queryAll<T>(name)`, answering 400 when a value doesn't parse.
+    pub fn query_all_as<T: FromStr>(&self, name: &str) -> Result<Vec<T>, UbiError> {
+        self.query_all(name)
+            .into_iter()
+            .map(|v| parse_query_value(name, v))
+            .collect()
+    }
+
+    /// `ubi.req.data`, the body parsed as JSON and checked against `T`. A body
+    /// that doesn't match answers 400 with every mismatching field.
+    pub fn data<T: DeserializeOwned + Validate>(&self) -> Result<T, UbiError> {
+        let value: Value = serde_json::from_slice(&self.body)?;
+        let mut errors = Vec::new();
+        T::validate(&value, "", &mut errors);
+        if !errors.is_empty() {
+            return Err(UbiError::new(400, "validation_error", "The request body doesn't match the expected type").fields(errors));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// The body parsed as JSON without the checks of `data`, for types that
+    /// don't implement `Validate`.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, UbiError> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// A field of the request body that doesn't match the declared type, listed
+/// under `fields` in the error sent back.
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    field: String,
+    message: String,
+}
+
+/// Checks a JSON value against a type before it is deserialized, so every
+/// mismatching field is reported instead of only the first one. The
+/// transpiler implements it for the `type`s declared in server.ts.
+pub trait Validate {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>);
+
+    /// Whether the field may be missing or `null`.
+    fn optional() -> bool {
+        false
+    }
+}
+
+fn field_error(errors: &mut Vec<FieldError>, path: &str, message: impl ToString) {
+    errors.push(FieldError {
+        field: path.to_string(),
+        message: message.to_string(),
+    });
+}
+
+fn json_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn expect_type(value: &Value, path: &str, errors: &mut Vec<FieldError>, expected: &str, matches: bool) {
+    if !matches {
+        field_error(errors, path, format!("expected {}, found {}", expected, json_type_name(value)));
+    }
+}
+
+/// Checks that `value` is an object and hands its fields to the checks of a
+/// generated `Validate` impl.
+pub fn validate_object<'a>(
+    value: &'a Value,
+    path: &str,
+    errors: &mut Vec<FieldError>,
+) -> Option<&'a serde_json::Map<String, Value>> {
+    let object = value.as_object();
+    expect_type(value, path, errors, "object", object.is_some());
+    object
+}
+
+pub fn validate_field<T: Validate>(
+    object: &serde_json::Map<String, Value>,
+    path: &str,
+    name: &str,
+    errors: &mut Vec<FieldError>,
+) {
+    let path = if path.is_empty() { name.to_string() } else { format!("{}.{}", path, name) };
+    match object.get(name) {
+        Some(value) => T::validate(value, &path, errors),
+        None if T::optional() => {}
+        None => field_error(errors, &path, "is required"),
+    }
+}
+
+/// The check of the enums lowered from unions of string literals.
+pub fn validate_variant(value: &Value, path: &str, errors: &mut Vec<FieldError>, variants: &[&str]) {
+    match value.as_str() {
+        Some(variant) if variants.contains(&variant) => {}
+        Some(_) => {
+            let expected: Vec<String> = variants.iter().map(|v| format!("{:?}", v)).collect();
+            field_error(errors, path, format!("expected one of {}", expected.join(", ")));
+        }
+        None => expect_type(value, path, errors, "string", false),
+    }
+}
+
+impl Validate for Value {
+    fn validate(_: &Value, _: &str, _: &mut Vec<FieldError>) {}
+}
+
+impl Validate for String {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        expect_type(value, path, errors, "string", value.is_string());
+    }
+}
+
+impl Validate for bool {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        expect_type(value, path, errors, "boolean", value.is_boolean());
+    }
+}
+
+impl Validate for i32 {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        let fits = value.as_i64().map_or(false, |n| i32::try_from(n).is_ok());
+        expect_type(value, path, errors, "integer", fits);
+    }
+}
+
+impl Validate for i64 {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        expect_type(value, path, errors, "integer", value.is_i64());
+    }
+}
+
+impl Validate for f64 {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        expect_type(value, path, errors, "number", value.is_number());
+    }
+}
+
+impl Validate for DateTime<Utc> {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        match value.as_str() {
+            Some(date) if DateTime::parse_from_rfc3339(date).is_ok() => {}
+            Some(_) => field_error(errors, path, "expected an RFC 3339 date, like 2024-01-31T12:00:00Z"),
+            None => expect_type(value, path, errors, "string", false),
+        }
+    }
+}
+
+impl<T: Validate> Validate for Option<T> {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        if !value.is_null() {
+            T::validate(value, path, errors);
+        }
+    }
+
+    fn optional() -> bool {
+        true
+    }
+}
+
+impl<T: Validate> Validate for Vec<T> {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        match value.as_array() {
+            Some(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    T::validate(item, &format!("{}[{}]", path, i), errors);
+                }
+            }
+            None => expect_type(value, path, errors, "array", false),
+        }
+    }
+}
+
+impl<T: Validate> Validate for HashMap<String, T> {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+        if let Some(object) = validate_object(value, path, errors) {
+            for name in object.keys() {
+                validate_field::<T>(object, path, name, errors);
+            }
+        }
+    }
+}
+
+fn parse_query_value<T: FromStr>(name: &str, value: &str) -> Result<T, UbiError> {
+    value
+        .parse()
+        .map_err(|_| UbiError::bad_request(format!("Query parameter `{}` has an invalid value `{}`", name, value)))
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+fn parse_cookies(header: &str) -> Vec<(String, String)> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().trim_matches('"').to_string()))
+        .collect()
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |b: u8| (b as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 3;
+                        continue;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+lazy_static! {
+    static ref APP_CONFIG: AppConfig = serde_json::from_str(CONFIG).expect("config.json is not valid");
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
 }
 
 pub struct PgConnection {
     client: Client,
+    // Several HTTP connections share one client, so a transaction needs the
+    // client to itself: queries take the lock shared, transactions exclusive.
+    tx_lock: Arc<RwLock<()>>,
+    in_transaction: Cell<bool>,
 }
 
 impl PgConnection {
     fn new(db_url: &str) -> Self {
         let client = may_postgres::connect(&db_url).unwrap();
-        PgConnection { client }
+        PgConnection {
+            client,
+            tx_lock: Arc::new(RwLock::new(())),
+            in_transaction: Cell::new(false),
+        }
     }
 
-    fn query(self: &Self, stmt: &str) -> Result<Vec<serde_json::Value>, may_postgres::Error> {
+    fn query(self: &Self, stmt: &str, params: &[SqlParam]) -> Result<Vec<serde_json::Value>, may_postgres::Error> {
+        let _guard = match self.in_transaction.get() {
+            true => None,
+            false => Some(self.tx_lock.read().unwrap_or_else(|e| e.into_inner())),
+        };
         let prepare = self.client.prepare(stmt)?;
-        //let _ = self.client.execute("prepare tes_stmt as select * from tes;", &[]).unwrap();
-        let query = self.client.query_raw(&prepare, &[])?;
-
-        let hasil: Vec<serde_json::Value> = query.map(|r| {
-                        let r = r.unwrap();
-                        let mut obj = serde_json::Map::new();
-                        for (i, col) in r.columns().iter().enumerate() {
-                let col_name = col.name().to_string();
-                let col_type = col.type_();
-
-                let col_value: serde_json::Value = if col_type == &postgres_types::Type::INT4 || col_type == &postgres_types::Type::INT8 {
-                        r.get::<_, Option<i64>>(i)
-                                .map(|v| serde_json::Value::Number(serde_json::Number::from(v)))
-                                .unwrap_or(serde_json::Value::Null)
-                        } else if col_type == &postgres_types::Type::FLOAT4 || col_type == &postgres_types::Type::FLOAT8 {
-                        r.get::<_, Option<f64>>(i)
-                                .map(|v| serde_json::Value::Number(serde_json::Number::from_f64(v).unwrap()))
-                                .unwrap_or(serde_json::Value::Null)
-                        } else if col_type == &postgres_types::Type::BOOL {
-                        r.get::<_, Option<bool>>(i).map(serde_json::Value::Bool).unwrap_or(serde_json::Value::Null)
-                        } else {
-                        r.get::<_, Option<&str>>(i)
-                                .map(|v| serde_json::Value::String(v.to_string()))
-                                .unwrap_or(serde_json::Value::Null)
-                        };
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p as &(dyn ToSql + Sync)).collect();
+        let query = self.client.query_raw(&prepare, &params)?;
 
-                obj.insert(col_name, col_value);
-            }
-                        serde_json::Value::Object(obj)
-                        })
-                    .collect();
+        let hasil: Vec<serde_json::Value> = query
+            .map(|r| {
+                let r = r?;
+                let mut obj = serde_json::Map::new();
+                for (i, col) in r.columns().iter().enumerate() {
+                    let col_value = r.try_get::<_, JsonColumn>(i)?;
+                    obj.insert(col.name().to_string(), col_value.0);
+                }
+                Ok(serde_json::Value::Object(obj))
+            })
+            .collect::<Result<_, may_postgres::Error>>()?;
 
         Ok(hasil)
     }
+
+    /// Runs `f` between BEGIN and COMMIT, rolling back when it returns an error.
+    fn transaction<T>(
+        self: &Self,
+        f: impl FnOnce() -> Result<T, UbiError>,
+    ) -> Result<T, UbiError> {
+        let _guard = self.tx_lock.write().unwrap_or_else(|e| e.into_inner());
+        self.client.batch_execute("BEGIN")?;
+        self.in_transaction.set(true);
+        let hasil = f();
+        self.in_transaction.set(false);
+
+        match hasil {
+            Ok(v) => {
+                self.client.batch_execute("COMMIT")?;
+                Ok(v)
+            }
+            Err(e) => {
+                let _ = self.client.batch_execute("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A value bound to a `$n` placeholder of `ubi.query`. The conversion to the
+/// wire format happens against the type PostgreSQL inferred for the
+/// placeholder, so `ubi.req.params` strings can be bound to integer columns.
+#[derive(Debug, Clone)]
+pub enum SqlParam {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Timestamp(DateTime<Utc>),
+}
+
+type SqlError = Box<dyn std::error::Error + Sync + Send>;
+
+impl SqlParam {
+    fn mismatch(&self, ty: &Type) -> SqlError {
+        format!("cannot bind {:?} to a parameter of type {}", self, ty).into()
+    }
+
+    fn as_i64(&self, ty: &Type) -> Result<i64, SqlError> {
+        match self {
+            SqlParam::Int(v) => Ok(*v),
+            SqlParam::Float(v) if v.fract() == 0.0 => Ok(*v as i64),
+            SqlParam::Text(v) => v.trim().parse().map_err(|_| self.mismatch(ty)),
+            _ => Err(self.mismatch(ty)),
+        }
+    }
+
+    fn as_f64(&self, ty: &Type) -> Result<f64, SqlError> {
+        match self {
+            SqlParam::Int(v) => Ok(*v as f64),
+            SqlParam::Float(v) => Ok(*v),
+            SqlParam::Text(v) => v.trim().parse().map_err(|_| self.mismatch(ty)),
+            _ => Err(self.mismatch(ty)),
+        }
+    }
+
+    fn as_bool(&self, ty: &Type) -> Result<bool, SqlError> {
+        match self {
+            SqlParam::Bool(v) => Ok(*v),
+            SqlParam::Int(0) => Ok(false),
+            SqlParam::Int(1) => Ok(true),
+            SqlParam::Text(v) => match v.trim() {
+                "true" | "t" | "1" => Ok(true),
+                "false" | "f" | "0" => Ok(false),
+                _ => Err(self.mismatch(ty)),
+            },
+            _ => Err(self.mismatch(ty)),
+        }
+    }
+
+    fn as_text(&self) -> String {
+        match self {
+            SqlParam::Null => String::new(),
+            SqlParam::Bool(v) => v.to_string(),
+            SqlParam::Int(v) => v.to_string(),
+            SqlParam::Float(v) => v.to_string(),
+            SqlParam::Text(v) => v.clone(),
+            SqlParam::Timestamp(v) => v.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        }
+    }
+}
+
+impl ToSql for SqlParam {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, SqlError> {
+        if let SqlParam::Null = self {
+            return Ok(IsNull::Yes);
+        }
+
+        // Dates come in JSON bodies and query strings as text.
+        let timestamp = match self {
+            SqlParam::Timestamp(v) => Some(*v),
+            SqlParam::Text(v) => DateTime::parse_from_rfc3339(v.trim()).ok().map(|v| v.with_timezone(&Utc)),
+            _ => None,
+        };
+        if *ty == Type::TIMESTAMPTZ {
+            return timestamp.ok_or_else(|| self.mismatch(ty))?.to_sql(ty, out);
+        } else if *ty == Type::TIMESTAMP {
+            return timestamp.ok_or_else(|| self.mismatch(ty))?.naive_utc().to_sql(ty, out);
+        } else if *ty == Type::DATE {
+            let date = match (timestamp, self) {
+                (Some(v), _) => v.date_naive(),
+                (None, SqlParam::Text(v)) => NaiveDate::parse_from_str(v.trim(), "%Y-%m-%d").map_err(|_| self.mismatch(ty))?,
+                _ => return Err(self.mismatch(ty)),
+            };
+            return date.to_sql(ty, out);
+        }
+
+        if *ty == Type::BOOL {
+            self.as_bool(ty)?.to_sql(ty, out)
+        } else if *ty == Type::INT2 {
+            i16::try_from(self.as_i64(ty)?).map_err(|_| self.mismatch(ty))?.to_sql(ty, out)
+        } else if *ty == Type::INT4 {
+            i32::try_from(self.as_i64(ty)?).map_err(|_| self.mismatch(ty))?.to_sql(ty, out)
+        } else if *ty == Type::INT8 {
+            self.as_i64(ty)?.to_sql(ty, out)
+        } else if *ty == Type::FLOAT4 {
+            (self.as_f64(ty)? as f32).to_sql(ty, out)
+        } else if *ty == Type::FLOAT8 {
+            self.as_f64(ty)?.to_sql(ty, out)
+        } else if *ty == Type::NUMERIC {
+            let text = match self {
+                SqlParam::Int(_) | SqlParam::Float(_) | SqlParam::Text(_) => self.as_text(),
+                _ => return Err(self.mismatch(ty)),
+            };
+            out.extend_from_slice(&numeric_from_str(&text).ok_or_else(|| self.mismatch(ty))?);
+            Ok(IsNull::No)
+        } else if *ty == Type::UUID {
+            let SqlParam::Text(v) = self else {
+                return Err(self.mismatch(ty));
+            };
+            uuid::Uuid::parse_str(v.trim()).map_err(|_| self.mismatch(ty))?.to_sql(ty, out)
+        } else if *ty == Type::JSON || *ty == Type::JSONB {
+            // Text that is JSON, like what `ubi.json` gives, goes as it is,
+            // any other text as a JSON string.
+            let value = match self {
+                SqlParam::Text(v) => serde_json::from_str(v).unwrap_or_else(|_| Value::String(v.clone())),
+                SqlParam::Bool(v) => Value::Bool(*v),
+                SqlParam::Int(v) => Value::from(*v),
+                SqlParam::Float(v) => Value::from(*v),
+                _ => Value::String(self.as_text()),
+            };
+            value.to_sql(ty, out)
+        } else if <&str as ToSql>::accepts(ty) {
+            self.as_text().as_str().to_sql(ty, out)
+        } else {
+            Err(format!("parameters of type {} are not supported, cast the placeholder in the query instead (for example $1::text::{})", ty, ty).into())
+        }
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+impl From<&str> for SqlParam {
+    fn from(v: &str) -> Self {
+        SqlParam::Text(v.to_string())
+    }
+}
+
+impl From<String> for SqlParam {
+    fn from(v: String) -> Self {
+        SqlParam::Text(v)
+    }
+}
+
+impl From<&String> for SqlParam {
+    fn from(v: &String) -> Self {
+        SqlParam::Text(v.clone())
+    }
+}
+
+impl From<bool> for SqlParam {
+    fn from(v: bool) -> Self {
+        SqlParam::Bool(v)
+    }
+}
+
+impl From<i32> for SqlParam {
+    fn from(v: i32) -> Self {
+        SqlParam::Int(v as i64)
+    }
+}
+
+impl From<i64> for SqlParam {
+    fn from(v: i64) -> Self {
+        SqlParam::Int(v)
+    }
+}
+
+impl From<f32> for SqlParam {
+    fn from(v: f32) -> Self {
+        SqlParam::Float(v as f64)
+    }
+}
+
+impl From<f64> for SqlParam {
+    fn from(v: f64) -> Self {
+        SqlParam::Float(v)
+    }
+}
+
+impl From<DateTime<Utc>> for SqlParam {
+    fn from(v: DateTime<Utc>) -> Self {
+        SqlParam::Timestamp(v)
+    }
+}
+
+impl From<&Value> for SqlParam {
+    fn from(v: &Value) -> Self {
+        match v {
+            Value::Null => SqlParam::Null,
+            Value::Bool(v) => SqlParam::Bool(*v),
+            Value::Number(n) => match n.as_i64() {
+                Some(v) => SqlParam::Int(v),
+                None => SqlParam::Float(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(v) => SqlParam::Text(v.clone()),
+            other => SqlParam::Text(other.to_string()),
+        }
+    }
+}
+
+impl From<Value> for SqlParam {
+    fn from(v: Value) -> Self {
+        SqlParam::from(&v)
+    }
+}
+
+impl<T: Into<SqlParam>> From<Option<T>> for SqlParam {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(SqlParam::Null)
+    }
+}
+
+/// A column decoded straight into JSON. Dates and times become ISO-8601
+/// strings, NUMERIC stays a string so no precision is lost, JSON/JSONB is
+/// embedded as is, BYTEA is base64 encoded and arrays map element-wise. A
+/// column of any other type is an error naming it.
+struct JsonColumn(Value);
+
+impl<'a> FromSql<'a> for JsonColumn {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, SqlError> {
+        let value = match *ty {
+            Type::BOOL => Value::Bool(bool::from_sql(ty, raw)?),
+            Type::CHAR => json!(i8::from_sql(ty, raw)?),
+            Type::INT2 => json!(i16::from_sql(ty, raw)?),
+            Type::INT4 => json!(i32::from_sql(ty, raw)?),
+            Type::INT8 => json!(i64::from_sql(ty, raw)?),
+            Type::OID => json!(u32::from_sql(ty, raw)?),
+            Type::FLOAT4 => float_to_json(f32::from_sql(ty, raw)? as f64),
+            Type::FLOAT8 => float_to_json(f64::from_sql(ty, raw)?),
+            Type::NUMERIC => Value::String(numeric_to_string(raw)?),
+            Type::DATE => Value::String(NaiveDate::from_sql(ty, raw)?.format("%Y-%m-%d").to_string()),
+            Type::TIME => Value::String(NaiveTime::from_sql(ty, raw)?.format("%H:%M:%S%.f").to_string()),
+            Type::TIMESTAMP => Value::String(
+                NaiveDateTime::from_sql(ty, raw)?
+                    .format("%Y-%m-%dT%H:%M:%S%.f")
+                    .to_string(),
+            ),
+            Type::TIMESTAMPTZ => Value::String(
+                DateTime::<Utc>::from_sql(ty, raw)?.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            ),
+            Type::UUID => Value::String(uuid::Uuid::from_sql(ty, raw)?.to_string()),
+            Type::JSON | Type::JSONB => Value::from_sql(ty, raw)?,
+            Type::BYTEA => Value::String(base64::engine::general_purpose::STANDARD.encode(raw)),
+            _ => match ty.kind() {
+                Kind::Array(_) => Value::Array(
+                    Vec::<JsonColumn>::from_sql(ty, raw)?
+                        .into_iter()
+                        .map(|v| v.0)
+                        .collect(),
+                ),
+                Kind::Domain(inner) => JsonColumn::from_sql(inner, raw)?.0,
+                Kind::Enum(_) => Value::String(<&str>::from_sql(ty, raw)?.to_string()),
+                _ if <&str as FromSql>::accepts(ty) => Value::String(<&str>::from_sql(ty, raw)?.to_string()),
+                // Columns come in the binary format, which for the other
+                // types isn't text.
+                _ => {
+                    return Err(format!(
+                        "columns of type {} can't be turned into JSON, cast the column to text in the query instead (for example {}::text)",
+                        ty,
+                        ty.name()
+                    )
+                    .into())
+                }
+            },
+        };
+
+        Ok(JsonColumn(value))
+    }
+
+    fn from_sql_null(_: &Type) -> Result<Self, SqlError> {
+        Ok(JsonColumn(Value::Null))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+fn float_to_json(v: f64) -> Value {
+    serde_json::Number::from_f64(v)
+        .map(Value::Number)
+        .unwrap_or_else(|| Value::String(v.to_string()))
+}
+
+/// The binary NUMERIC format of a decimal like `-12.50`, `NaN` or
+/// `Infinity`, `None` when `text` isn't one.
+fn numeric_from_str(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    // The number of digits, the weight of the first one, the sign and the
+    // number of decimal digits shown, then the digits.
+    let words: Vec<u16> = match (negative, unsigned.to_ascii_lowercase().as_str()) {
+        (false, "nan") => vec![0, 0, 0xC000, 0],
+        (false, "infinity" | "inf") => vec![0, 0, 0xD000, 0],
+        (true, "infinity" | "inf") => vec![0, 0, 0xF000, 0],
+        _ => {
+            let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+            let decimal = format!("{}{}", integer, fraction);
+            if decimal.is_empty() || !decimal.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let dscale = fraction.len() as u16;
+
+            // Base 10000 digits lined up on the decimal point.
+            let integer = format!("{}{}", "0".repeat((4 - integer.len() % 4) % 4), integer);
+            let fraction = format!("{}{}", fraction, "0".repeat((4 - fraction.len() % 4) % 4));
+            let mut digits: Vec<u16> = format!("{}{}", integer, fraction)
+                .as_bytes()
+                .chunks(4)
+                .map(|chunk| chunk.iter().fold(0, |n, digit| n * 10 + (digit - b'0') as u16))
+                .collect();
+            let mut weight = (integer.len() / 4) as i16 - 1;
+            while digits.first() == Some(&0) {
+                digits.remove(0);
+                weight -= 1;
+            }
+            while digits.last() == Some(&0) {
+                digits.pop();
+            }
+
+            if digits.is_empty() {
+                vec![0, 0, 0, dscale]
+            } else {
+                let sign = if negative { 0x4000 } else { 0 };
+                [digits.len() as u16, weight as u16, sign, dscale].into_iter().chain(digits).collect()
+            }
+        }
+    };
+
+    Some(words.iter().flat_map(|word| word.to_be_bytes()).collect())
+}
+
+/// Renders the binary NUMERIC format (base 10000 digits) as a decimal string.
+fn numeric_to_string(raw: &[u8]) -> Result<String, SqlError> {
+    let read = |i: usize| -> Result<u16, SqlError> {
+        raw.get(i..i + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| "invalid numeric value".into())
+    };
+
+    let ndigits = read(0)? as usize;
+    let weight = read(2)? as i16 as i64;
+    let sign = read(4)?;
+    let dscale = read(6)? as usize;
+    let digits = (0..ndigits)
+        .map(|i| read(8 + i * 2))
+        .collect::<Result<Vec<_>, _>>()?;
+    let digit = |i: i64| -> u16 {
+        if i < 0 {
+            0
+        } else {
+            digits.get(i as usize).copied().unwrap_or(0)
+        }
+    };
+
+    match sign {
+        0xC000 => return Ok("NaN".to_string()),
+        0xD000 => return Ok("Infinity".to_string()),
+        0xF000 => return Ok("-Infinity".to_string()),
+        _ => {}
+    }
+
+    let mut hasil = String::new();
+    if sign == 0x4000 {
+        hasil.push('-');
+    }
+
+    if weight < 0 {
+        hasil.push('0');
+    } else {
+        for i in 0..=weight {
+            if i == 0 {
+                hasil.push_str(&digit(i).to_string());
+            } else {
+                hasil.push_str(&format!("{:04}", digit(i)));
+            }
+        }
+    }
+
+    if dscale > 0 {
+        let mut fraction = String::new();
+        for k in 1..=((dscale + 3) / 4) as i64 {
+            fraction.push_str(&format!("{:04}", digit(weight + k)));
+        }
+        fraction.truncate(dscale);
+        hasil.push('.');
+        hasil.push_str(&fraction);
+    }
+
+    Ok(hasil)
 }
 
 struct PgPool {
@@ -151,6 +1167,8 @@ impl PgPool {
         // assert_eq!(connection.client.id() % len, id % len);
         PgConnection {
             client: connection.client.clone(),
+            tx_lock: connection.tx_lock.clone(),
+            in_transaction: Cell::new(false),
         }
     }
 }
@@ -158,158 +1176,86 @@ impl PgPool {
 
 impl HttpService for Context {
     fn call(&mut self, req: Request, res: &mut Response) -> io::Result<()> {
-        match req.path() {
-            path if path.starts_with("/api") => {
-                let mut isi = String::new();
-
-                match req.method() {
-                    "GET" => {
-                        isi = match server::ROUTES.get(format_compact!("{}/get", path.strip_suffix("/").unwrap_or(path)).as_str()) {
-                                                Some(handler) => {
-                                                match handler(&self.db, req) {
-                                                        Ok(response) =>response,
-                                                        Err(e) => format!("Error: {}", e),
-                                                    }
-                                            }
-                                                None => {
-                                                    let url = format!("{}/{}", path.strip_suffix("/").unwrap_or(path), req.method().to_lowercase());
-
-                                                    match match_url(&url) {
-                                                        Some((handler, params)) => handler(&self.db, req, &params).unwrap(),
-                                                        None =>  format!("404 Not Found"),
-                                                    }
-                                                }
-                                            };
-
-                    },
-                    "POST" => {
-                        isi = match server::ROUTES.get(format_compact!("{}/post", path.strip_suffix("/").unwrap_or(path)).as_str()) {
-                                        Some(handler) => {
-                                                match handler(&self.db, req) {
-                                                Ok(response) => response,
-                                                Err(e) => format!("Error: {}", e),
-                                            }
-                                            }
-                                        None => {
-                                            let url = format!("{}/{}", path.strip_suffix("/").unwrap_or(path), req.method().to_lowercase());
-
-                                                    match match_url(&url) {
-                                                        Some((handler, params)) => handler(&self.db, req, &params).unwrap(),
-                                                        None =>  format!("404 Not Found"),
-                                                    }
-
-                                        },
-                                    };
-
-                    },
-                    "UPDATE" => {
-                        isi = match server::ROUTES.get(format_compact!("{}/update", path.strip_suffix("/").unwrap_or(path)).as_str()) {
-                                                Some(handler) => {
-                                                match handler(&self.db, req) {
-                                                        Ok(response) =>response,
-                                                        Err(e) => format!("Error: {}", e),
-                                                    }
-                                            }
-                                                None => {
-                                                    let url = format!("{}/{}", path.strip_suffix("/").unwrap_or(path), req.method().to_lowercase());
-
-                                                    match match_url(&url) {
-                                                        Some((handler, params)) => handler(&self.db, req, &params).unwrap(),
-                                                        None =>  format!("404 Not Found"),
-                                                    }
-
-                                                },
-                                            };
-
-                    },
-                    "DELETE" => {
-                        isi = match server::ROUTES.get(format_compact!("{}/delete", path.strip_suffix("/").unwrap_or(path)).as_str()) {
-                        Some(handler) => {
-                                match handler(&self.db, req) {
-                                        Ok(response) =>response,
-                                        Err(e) => format!("Error: {}", e),
-                                    }
-                            }
-                            None => {
-                                let url = format!("{}/{}", path.strip_suffix("/").unwrap_or(path), req.method().to_lowercase());
+        let target = req.path();
+        let path = target.split_once('?').map(|(path, _)| path).unwrap_or(target).to_string();
 
-                                                    match match_url(&url) {
-                                                        Some((handler, params)) => handler(&self.db, req, &params).unwrap(),
-                                                        None =>  format!("404 Not Found"),
-                                                    }
+        match path.as_str() {
+            path if path.starts_with("/api") => {
+                let method = req.method().to_ascii_lowercase();
 
-                            },
+                let response = match match_route(path) {
+                    None => UbiError::not_found(format!("No route matches {}", path)).into(),
+                    Some((node, params)) => {
+                        let handler = match (node.handler(&method), method.as_str()) {
+                            (None, "head") => node.handler("get"),
+                            (handler, _) => handler,
                         };
+                        match handler {
+                            Some(handler) => match UbiRequest::new(req, params) {
+                                Ok(req) => {
+                                    let response = handler(&self.db, req).unwrap_or_else(UbiResponse::from);
+                                    match method.as_str() {
+                                        "head" => response.body(""),
+                                        _ => response,
+                                    }
+                                }
+                                Err(e) => e.into(),
+                            },
+                            None if method == "options" => UbiResponse::new()
+                                .status(204)
+                                .header("allow", node.allowed_methods()),
+                            None => UbiResponse::from(UbiError::new(
+                                405,
+                                "method_not_allowed",
+                                format!("{} does not accept {}", path, req.method()),
+                            ))
+                            .header("allow", node.allowed_methods()),
+                        }
+                    }
+                };
 
-                    },
-                    _ => isi = "not found".to_string()
-                }
-
-                res.header("content-type: application/json").body_vec(isi.into_bytes());
+                response.write(res, &mut self.header_lines);
             }
             path if path.starts_with("/parts") => {
-                if !req.path().ends_with("/") {
-                    // println!("path 1: {}", req.path().strip_prefix("/parts/").unwrap());
-                    match Frontend::get(&format_compact!("{}/ui.js", req.path().strip_prefix("/parts/").unwrap())) {
-                        Some(isi) => {
-                            res.header("content-type: text/plain").body_vec(isi.data.to_vec());
-                        }
-                        None => {
-                            res.header("content-type: text/plain").body("not found");
-                        }
-                    };
-                } else {
-                    let mut path = req.path().strip_prefix("/parts/").unwrap();
-                    path = path.strip_prefix("/").unwrap_or(path);
-                    // println!("path sebelum: {}, path 2: {}", req.path(), path);
-                    let part = Frontend::get(&format_compact!("{}ui.js", path)).unwrap();
-                    res.header("content-type: text/plain").body_vec(part.data.to_vec());
-                }
-            }
-            "/favicon.ico" => {
-                match fs::read(format_compact!("./static/{}", req.path())) {
-                    Ok(contents) => {
-                        res.header(get_mime_type(req.path().to_string()))
-                            .body_vec(contents);
-                        Ok::<(), io::Error>(())
+                let part = path.strip_prefix("/parts").unwrap_or(path).trim_matches('/');
+                let file = match part.is_empty() {
+                    true => format_compact!("ui.js"),
+                    false => format_compact!("{}/ui.js", part),
+                };
+                match Frontend::get(&file) {
+                    Some(isi) => {
+                        res.header("content-type: text/plain").body_vec(isi.data.to_vec());
                     }
-                    Err(_) => {
-                        res.status_code(404, "not found");
-                        Ok(())
-                    }
-                }?;
-
+                    None => UbiResponse::from(UbiError::not_found(format!("No page part at {}", path))).write(res, &mut self.header_lines),
+                };
             }
-            path if path.starts_with("/static") => {
-                let path = req.path();
-                match fs::read(format_compact!(".{}", path)) {
+            path if path == "/favicon.ico" || path.starts_with("/static") => {
+                let file = match path {
+                    "/favicon.ico" => format_compact!("./static{}", path),
+                    _ => format_compact!(".{}", path),
+                };
+                match fs::read(&file) {
                     Ok(contents) => {
-                        res.header(get_mime_type(path.to_string()))
-                            .body_vec(contents);
-                        Ok::<(), io::Error>(())
-                    }
-                    Err(_) => {
-                        res.status_code(404, "not found");
-                        Ok(())
+                        res.header(get_mime_type(path.to_string())).body_vec(contents);
                     }
-                }?;
-                return Ok(());
+                    Err(e) => UbiResponse::from(
+                        UbiError::not_found(format!("No static file at {}", path)).details(e),
+                    )
+                    .write(res, &mut self.header_lines),
+                };
             }
-            _ => {
-                if !req.path().ends_with("/") {
-                    match Frontend::get(&format_compact!("{}/index.html", req.path().strip_prefix("/").unwrap())) {
-                        Some(isi) => {
-                            res.header("content-type: text/html").body_vec(isi.data.to_vec());
-                        }
-                        None => {
-                            res.header("content-type: text/html").body("not found");
-                        }
-                    };
-                } else {
-                    let path = req.path().strip_prefix("/").unwrap();
-                    let index = Frontend::get(&format_compact!("{}index.html", path)).unwrap();
-                    res.header("content-type: text/html").body_vec(index.data.to_vec());
-                }
+            path => {
+                let page = path.trim_matches('/');
+                let file = match page.is_empty() {
+                    true => format_compact!("index.html"),
+                    false => format_compact!("{}/index.html", page),
+                };
+                match Frontend::get(&file) {
+                    Some(isi) => {
+                        res.header("content-type: text/html").body_vec(isi.data.to_vec());
+                    }
+                    None => UbiResponse::from(UbiError::not_found(format!("No page at {}", path))).write(res, &mut self.header_lines),
+                };
             }
         }
         Ok(())
@@ -326,6 +1272,7 @@ impl HttpServiceFactory for Server {
     fn new_service(&self, id: usize) -> Self::Service {
         Context {
                db: self.db_pool.get_connection(id),
+               header_lines: Vec::new(),
         }
     }
 }
@@ -342,33 +1289,89 @@ fn get_mime_type(path: String) -> &'static str {
 }
 
 
-pub fn parameterized_url(pattern: &str, url: &str) -> Option<std::collections::HashMap<String, String>> {
-    let re_str = Regex::new(r":([^/]+)").unwrap();
-    let regex_pattern = re_str.replace_all(pattern, r"([^/]+)");
-    let full_regex = format!("^{}$", regex_pattern);
-    let re = Regex::new(&full_regex).unwrap();
+/// HTTP methods a route file can export a handler for, in `Allow` order.
+const METHODS: [&str; 7] = ["get", "head", "post", "put", "patch", "delete", "options"];
 
-    let captures = re.captures(url)?;
+/// One node of the route trie `ubi build` writes into server/mod.rs. Children
+/// are tried static segment first, then `:param`, then the `*wildcard`.
+pub struct RouteNode {
+    /// Sorted by segment so they can be binary searched.
+    pub statics: &'static [(&'static str, usize)],
+    pub param: Option<(&'static str, usize)>,
+    pub wildcard: Option<(&'static str, usize)>,
+    pub handlers: &'static [(&'static str, server::HandlerFn)],
+}
 
-    let mut params = std::collections::HashMap::new();
-    for (i, cap) in re_str.captures_iter(pattern).enumerate() {
-        if let Some(param) = cap.get(1) {
-            params.insert(param.as_str().to_string(), captures[i + 1].to_string());
-        }
+impl RouteNode {
+    fn handler(&self, method: &str) -> Option<server::HandlerFn> {
+        self.handlers
+            .iter()
+            .find(|(m, _)| *m == method)
+            .map(|(_, route)| *route)
     }
 
-    Some(params)
+    /// Methods this route answers to, for the `Allow` header of 405 and OPTIONS.
+    fn allowed_methods(&self) -> String {
+        let has = |method: &str| self.handler(method).is_some();
+        METHODS
+            .iter()
+            .filter(|m| has(m) || (**m == "head" && has("get")) || **m == "options")
+            .map(|m| m.to_ascii_uppercase())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn match_route(path: &str) -> Option<(&'static RouteNode, HashMap<String, String>)> {
+    let (node, params) = find_route(server::ROUTER, path)?;
+    Some((&server::ROUTER[node], params))
 }
 
-fn match_url(url: &str) -> Option<(server::HandlerFn2, HashMap<String, String>)> {
-    for (pattern, handler) in server::PARAMETERIZED_ROUTES.iter() {
-        if let Some(params) = parameterized_url(pattern, url) {
-            return Some((*handler, params));
+/// The node of `router` serving `path` and the values of its parameters.
+fn find_route(router: &[RouteNode], path: &str) -> Option<(usize, HashMap<String, String>)> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let mut params = Vec::new();
+    let node = match_node(router, 0, &segments, &mut params)?;
+    let params = params
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), percent_decode(&value, false)))
+        .collect();
+    Some((node, params))
+}
+
+fn match_node(
+    router: &[RouteNode],
+    index: usize,
+    segments: &[&str],
+    params: &mut Vec<(&'static str, String)>,
+) -> Option<usize> {
+    let node = &router[index];
+    let Some((segment, rest)) = segments.split_first() else {
+        return match node.handlers.is_empty() {
+            true => None,
+            false => Some(index),
+        };
+    };
+
+    if let Ok(i) = node.statics.binary_search_by(|(s, _)| (*s).cmp(segment)) {
+        if let Some(found) = match_node(router, node.statics[i].1, rest, params) {
+            return Some(found);
+        }
+    }
+
+    if let Some((name, child)) = node.param {
+        params.push((name, segment.to_string()));
+        if let Some(found) = match_node(router, child, rest, params) {
+            return Some(found);
         }
-        println!("pattern : {}", pattern);
-        println!("url : {}", url);
+        params.pop();
+    }
+
+    if let Some((name, child)) = node.wildcard {
+        params.push((name, segments.join("/")));
+        return Some(child);
     }
-    println!("failed to loop parameterized loop");
+
     None
 }
 
@@ -376,7 +1379,7 @@ fn main() -> io::Result<()> {
 
     may::config().set_pool_capacity(1000).set_stack_size(0x1000);
 
-    let app_config: AppConfig = serde_json::from_str(CONFIG).unwrap();
+    let app_config = &*APP_CONFIG;
     println!("{:?}", app_config);
 
     let db_url = format!(
@@ -402,3 +1405,264 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(_: &PgConnection, _: UbiRequest) -> Result<UbiResponse, UbiError> {
+        Ok(UbiResponse::new())
+    }
+
+    /// The table `ubi build` generates for the routes
+    /// users, users/me, users/:id, users/:id/posts, files/*path and
+    /// files/readme/raw.
+    static ROUTER: &[RouteNode] = &[
+        // 0
+        RouteNode { statics: &[("api", 1)], param: None, wildcard: None, handlers: &[] },
+        // 1
+        RouteNode { statics: &[("files", 2), ("users", 3)], param: None, wildcard: None, handlers: &[] },
+        // 2
+        RouteNode { statics: &[("readme", 4)], param: None, wildcard: Some(("path", 5)), handlers: &[] },
+        // 3
+        RouteNode { statics: &[("me", 6)], param: Some(("id", 7)), wildcard: None, handlers: &[("get", handler)] },
+        // 4
+        RouteNode { statics: &[("raw", 8)], param: None, wildcard: None, handlers: &[] },
+        // 5
+        RouteNode { statics: &[], param: None, wildcard: None, handlers: &[("get", handler)] },
+        // 6
+        RouteNode { statics: &[], param: None, wildcard: None, handlers: &[("get", handler)] },
+        // 7
+        RouteNode { statics: &[("posts", 9)], param: None, wildcard: None, handlers: &[("get", handler)] },
+        // 8
+        RouteNode { statics: &[], param: None, wildcard: None, handlers: &[("get", handler)] },
+        // 9
+        RouteNode { statics: &[], param: None, wildcard: None, handlers: &[("get", handler)] },
+    ];
+
+    fn route(path: &str) -> Option<(usize, Vec<(String, String)>)> {
+        let (node, params) = find_route(ROUTER, path)?;
+        let mut params: Vec<(String, String)> = params.into_iter().collect();
+        params.sort();
+        Some((node, params))
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn static_segments_win_over_parameters_and_catch_alls() {
+        assert_eq!(route("/api/users"), Some((3, params(&[]))));
+        assert_eq!(route("/api/users/me"), Some((6, params(&[]))));
+        assert_eq!(route("/api/users/42"), Some((7, params(&[("id", "42")]))));
+        assert_eq!(route("/api/files/readme/raw"), Some((8, params(&[]))));
+        assert_eq!(route("/api/files/a/b.txt"), Some((5, params(&[("path", "a/b.txt")]))));
+    }
+
+    #[test]
+    fn a_dead_end_falls_back_to_the_next_kind_of_segment() {
+        assert_eq!(route("/api/users/me/posts"), Some((9, params(&[("id", "me")]))));
+        assert_eq!(route("/api/files/readme/other"), Some((5, params(&[("path", "readme/other")]))));
+        assert_eq!(route("/api/files/readme"), Some((5, params(&[("path", "readme")]))));
+    }
+
+    #[test]
+    fn trailing_and_repeated_slashes_are_ignored() {
+        assert_eq!(route("/api/users/"), Some((3, params(&[]))));
+        assert_eq!(route("/api//users//42/"), Some((7, params(&[("id", "42")]))));
+    }
+
+    #[test]
+    fn routes_without_handlers_do_not_match() {
+        assert_eq!(route("/api"), None);
+        assert_eq!(route("/api/files"), None);
+        assert_eq!(route("/api/nothing"), None);
+        assert_eq!(route("/api/users/42/posts/1"), None);
+    }
+
+    #[test]
+    fn parameters_are_percent_decoded() {
+        assert_eq!(route("/api/users/a%20b"), Some((7, params(&[("id", "a b")]))));
+    }
+
+    /// Encodes `value` as Postgres sends a column of type `ty` and decodes it.
+    fn column<T: ToSql>(ty: Type, value: T) -> Value {
+        let mut raw = BytesMut::new();
+        value.to_sql(&ty, &mut raw).unwrap();
+        JsonColumn::from_sql(&ty, &raw).unwrap().0
+    }
+
+    /// A NUMERIC in the binary format: base 10000 digits, the weight of the
+    /// first one, the sign and the number of decimal digits shown.
+    fn numeric(weight: i16, sign: u16, dscale: u16, digits: &[u16]) -> String {
+        let mut raw = Vec::new();
+        for word in [digits.len() as u16, weight as u16, sign, dscale].iter().chain(digits) {
+            raw.extend_from_slice(&word.to_be_bytes());
+        }
+        numeric_to_string(&raw).unwrap()
+    }
+
+    #[test]
+    fn numeric_keeps_every_digit() {
+        assert_eq!(numeric(0, 0, 0, &[]), "0");
+        assert_eq!(numeric(0, 0, 2, &[]), "0.00");
+        assert_eq!(numeric(0, 0, 0, &[42]), "42");
+        assert_eq!(numeric(0, 0x4000, 0, &[42]), "-42");
+        assert_eq!(numeric(0, 0x4000, 1, &[1, 5000]), "-1.5");
+        assert_eq!(numeric(0, 0, 4, &[3, 1415]), "3.1415");
+        assert_eq!(numeric(-1, 0, 2, &[2500]), "0.25");
+        assert_eq!(numeric(-2, 0, 5, &[1000]), "0.00001");
+        assert_eq!(numeric(1, 0, 0, &[1, 0]), "10000");
+        assert_eq!(numeric(5, 0, 0, &[1]), "100000000000000000000");
+        assert_eq!(
+            numeric(4, 0, 10, &[1234, 5678, 9012, 3456, 7890, 1234, 5678, 9000]),
+            "12345678901234567890.1234567890"
+        );
+        assert_eq!(numeric(0, 0xC000, 0, &[]), "NaN");
+        assert_eq!(numeric(0, 0xD000, 0, &[]), "Infinity");
+        assert_eq!(numeric(0, 0xF000, 0, &[]), "-Infinity");
+        assert!(numeric_to_string(&[0, 2, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+    }
+
+    /// Binds `param` to a parameter of type `ty` and decodes it like a
+    /// column of that type.
+    fn bound(ty: Type, param: impl Into<SqlParam>) -> Value {
+        let mut raw = BytesMut::new();
+        param.into().to_sql(&ty, &mut raw).unwrap();
+        JsonColumn::from_sql(&ty, &raw).unwrap().0
+    }
+
+    #[test]
+    fn numeric_parameters_keep_every_digit() {
+        for text in ["0", "0.00", "42", "-1.5", "3.1415", "0.00001", "10000", "100000000000000000000", "NaN", "-Infinity"] {
+            assert_eq!(numeric_to_string(&numeric_from_str(text).unwrap()).unwrap(), text);
+        }
+        assert_eq!(numeric_to_string(&numeric_from_str("+007.50").unwrap()).unwrap(), "7.50");
+        assert_eq!(numeric_to_string(&numeric_from_str("-0").unwrap()).unwrap(), "0");
+        assert!(numeric_from_str("1e5").is_none());
+        assert!(numeric_from_str(".").is_none());
+        assert_eq!(bound(Type::NUMERIC, 12), json!("12"));
+        assert_eq!(bound(Type::NUMERIC, "19.99"), json!("19.99"));
+        assert_eq!(bound(Type::NUMERIC, 0.25), json!("0.25"));
+    }
+
+    #[test]
+    fn text_parameters_bind_to_uuid_json_and_dates() {
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert_eq!(bound(Type::UUID, id), json!(id));
+        assert_eq!(bound(Type::JSONB, r#"{"tags": [1, 2]}"#), json!({"tags": [1, 2]}));
+        assert_eq!(bound(Type::JSON, "hello"), json!("hello"));
+        assert_eq!(bound(Type::JSONB, &json!({"a": null})), json!({"a": null}));
+        assert_eq!(bound(Type::JSONB, 3), json!(3));
+        assert_eq!(bound(Type::DATE, "2024-02-29"), json!("2024-02-29"));
+        assert_eq!(bound(Type::TIMESTAMPTZ, "2024-02-29T13:05:09+07:00"), json!("2024-02-29T06:05:09Z"));
+
+        let mut raw = BytesMut::new();
+        assert!(SqlParam::from("nope").to_sql(&Type::UUID, &mut raw).is_err());
+        assert!(SqlParam::from(true).to_sql(&Type::NUMERIC, &mut raw).is_err());
+        assert!(SqlParam::from("29/02/2024").to_sql(&Type::DATE, &mut raw).is_err());
+    }
+
+    #[test]
+    fn columns_map_to_json() {
+        assert_eq!(column(Type::BOOL, true), json!(true));
+        assert_eq!(column(Type::CHAR, 65i8), json!(65));
+        assert_eq!(column(Type::INT2, -7i16), json!(-7));
+        assert_eq!(column(Type::INT4, 2_000_000_000i32), json!(2_000_000_000));
+        assert_eq!(column(Type::INT8, i64::MAX), json!(i64::MAX));
+        assert_eq!(column(Type::OID, 4_000_000_000u32), json!(4_000_000_000u32));
+        assert_eq!(column(Type::FLOAT4, 1.5f32), json!(1.5));
+        assert_eq!(column(Type::FLOAT8, -0.25f64), json!(-0.25));
+        assert_eq!(column(Type::FLOAT8, f64::NAN), json!("NaN"));
+        assert_eq!(column(Type::TEXT, "héllo"), json!("héllo"));
+        assert_eq!(column(Type::VARCHAR, "a"), json!("a"));
+        assert_eq!(column(Type::BYTEA, &[0u8, 1, 255][..]), json!("AAH/"));
+    }
+
+    #[test]
+    fn dates_and_times_are_iso_8601() {
+        let date = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        let time = NaiveTime::from_hms_micro_opt(13, 5, 9, 250_000).unwrap();
+        let timestamp = NaiveDateTime::new(date, time);
+        assert_eq!(column(Type::DATE, date), json!("2024-02-29"));
+        assert_eq!(column(Type::TIME, time), json!("13:05:09.250"));
+        assert_eq!(column(Type::TIMESTAMP, timestamp), json!("2024-02-29T13:05:09.250"));
+        assert_eq!(
+            column(Type::TIMESTAMPTZ, timestamp.and_utc()),
+            json!("2024-02-29T13:05:09.250Z")
+        );
+    }
+
+    #[test]
+    fn uuid_json_and_arrays_keep_their_shape() {
+        let id = uuid::Uuid::from_u128(0x67e55044_10b1_426f_9247_bb680e5fe0c8);
+        assert_eq!(column(Type::UUID, id), json!("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        let document = json!({"name": "ubi", "tags": [1, 2], "nested": {"ok": null}});
+        assert_eq!(column(Type::JSON, document.clone()), document);
+        assert_eq!(column(Type::JSONB, document.clone()), document);
+        assert_eq!(column(Type::INT4_ARRAY, vec![1i32, 2, 3]), json!([1, 2, 3]));
+        assert_eq!(column(Type::TEXT_ARRAY, vec![Some("a"), None]), json!(["a", null]));
+        assert_eq!(column(Type::JSONB_ARRAY, vec![json!({"a": 1})]), json!([{"a": 1}]));
+        assert_eq!(JsonColumn::from_sql_null(&Type::NUMERIC).unwrap().0, Value::Null);
+    }
+
+    #[test]
+    fn columns_of_other_types_are_an_error_naming_the_type() {
+        let point = [0u8; 16];
+        let Err(e) = JsonColumn::from_sql(&Type::POINT, &point) else {
+            panic!("a point column turned into JSON")
+        };
+        assert!(e.to_string().contains("columns of type point"), "{}", e);
+    }
+
+    /// The matcher the trie replaced: a map of the static routes, then a
+    /// regex built for every parameterized route until one matches.
+    fn linear_match(statics: &HashMap<&str, usize>, patterns: &[(&str, usize)], path: &str) -> Option<usize> {
+        let path = path.strip_suffix('/').unwrap_or(path);
+        if let Some(node) = statics.get(path) {
+            return Some(*node);
+        }
+        let name = regex::Regex::new(r":([^/]+)").unwrap();
+        patterns.iter().find_map(|(pattern, node)| {
+            let pattern = format!("^{}$", name.replace_all(pattern, "([^/]+)"));
+            regex::Regex::new(&pattern).unwrap().is_match(path).then_some(*node)
+        })
+    }
+
+    fn forwarded(headers: &[&str]) -> UbiRequest {
+        UbiRequest {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: headers.iter().map(|v| ("x-forwarded-for".to_string(), v.to_string())).collect(),
+            cookies: Vec::new(),
+            params: HashMap::new(),
+            query: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ip_is_the_entry_the_trusted_proxies_added() {
+        let req = forwarded(&["6.6.6.6, 1.1.1.1", "10.0.0.2"]);
+        assert_eq!(req.forwarded_ip(1), Some("10.0.0.2"));
+        assert_eq!(req.forwarded_ip(2), Some("1.1.1.1"));
+        assert_eq!(req.forwarded_ip(4), None);
+        assert_eq!(forwarded(&["1.1.1.1"]).forwarded_ip(0), None);
+
+        let mut req = forwarded(&[]);
+        assert_eq!(req.forwarded_ip(1), None);
+        req.headers.push(("x-real-ip".to_string(), "2.2.2.2".to_string()));
+        assert_eq!(req.forwarded_ip(1), Some("2.2.2.2"));
+    }
+
+    #[test]
+    fn trie_finds_what_the_linear_matcher_found() {
+        let statics = HashMap::from([("/api/users", 3), ("/api/users/me", 6), ("/api/files/readme/raw", 8)]);
+        let patterns = [("/api/users/:id", 7), ("/api/users/:id/posts", 9)];
+        let paths = ["/api/users", "/api/users/me", "/api/users/42", "/api/users/42/posts", "/api/files/readme/raw", "/api/nope"];
+        for path in paths {
+            assert_eq!(linear_match(&statics, &patterns, path), route(path).map(|(node, _)| node), "{}", path);
+        }
+    }
+}
@@ -1,16 +1,23 @@
 
-use std::collections::HashMap;
-use lazy_static::lazy_static;
-use crate::PgConnection;
+use crate::{PgConnection, RouteNode, UbiError, UbiRequest, UbiResponse};
 
 pub mod tes;
 
-pub type HandlerFn = fn(&PgConnection) -> Result<String, may_postgres::Error>;
+pub type HandlerFn = fn(&PgConnection, UbiRequest) -> Result<UbiResponse, UbiError>;
 
-lazy_static! {
-    pub static ref ROUTES: HashMap<&'static str, HandlerFn> = {
-        let mut map = HashMap::new();
-        map.insert("/tes", tes::get as HandlerFn);;
-        map
-    };
-}
+pub static ROUTER: &[RouteNode] = &[
+    // 0
+    RouteNode {
+        statics: &[("tes", 1)],
+        param: None,
+        wildcard: None,
+        handlers: &[],
+    },
+    // 1
+    RouteNode {
+        statics: &[],
+        param: None,
+        wildcard: None,
+        handlers: &[("get", tes::get as HandlerFn)],
+    },
+];
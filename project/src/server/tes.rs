@@ -1,12 +1,3 @@
-use std::collections::HashMap;
-pub type HandlerFn = fn() -> &'static str;
-pub mod mod;
-pub mod tes;
-
-pub fn generate_routes() -> HashMap<&'static str, HandlerFn> {
-let mut map = HashMap::new();
-    map.insert("/mod", mod::get);
-    map.insert("/tes", tes::get);
-
-map
-}
\ No newline at end of file
+pub fn get(db: &crate::PgConnection, req: crate::UbiRequest) -> Result<crate::UbiResponse, crate::UbiError> {
+    return Ok(crate::UbiResponse::from(serde_json::json!(&"tes".to_string()).to_string()));
+}
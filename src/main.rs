@@ -6,32 +6,40 @@
 )]
 
 use ::std::{
+    collections::HashMap,
     env, fs,
     fs::File,
     io,
     io::Write,
     path::{Path, PathBuf},
     process::{Command as StdCommand, Stdio},
-    os::unix::fs as unix_fs,
+    os::unix::fs::{self as unix_fs, PermissionsExt},
 };
 use clap::{Arg, Command};
 use include_dir::{include_dir, Dir};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde_json::Value;
-use uuid::Uuid;
 use walkdir::WalkDir;
 // use compact_str::{format_compact, CompactString, ToCompactString};
 
+mod rewrite;
+mod source_map;
+mod ts;
+mod ubi;
+
+use source_map::SourceMap;
+
 const CARGO_TOML: &str = include_str!("../project/Cargo.toml");
 const MAIN_RS: &str = include_str!("../project/src/main.rs");
 const CONFIG: &str = include_str!("../project/config.json");
 const ROUTES_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/routes");
 const INDEX_HTML: &str = include_str!("../libs/index.html");
-const DPRINT_CONFIG: &str = include_str!("../dprint.json");
 const CB: &[u8] = include_bytes!("../libs/cb");
 const PN: &[u8] = include_bytes!("../libs/pn");
-const DP: &[u8] = include_bytes!("../libs/dp");
+/// The py2many libs/pn is the console script of, and whose output the
+/// templates of `convert_py_to_rust` are written against.
+const PY2MANY_VERSION: &str = "0.4";
 
 lazy_static! {
     static ref UBI_PATH: PathBuf =
@@ -48,6 +56,14 @@ fn build_ubi() -> io::Result<()> {
         .output()
         .expect("Compiling failed");
 
+    if Path::new("./lib").exists() {
+        StdCommand::new("cp")
+            .arg("-r")
+            .arg("./lib")
+            .arg("./.project_build")
+            .output()?;
+    }
+
     let client_dir = Path::new("./.project_build/routes");
     if !client_dir.exists() {
         return Err(io::Error::new(
@@ -56,11 +72,91 @@ fn build_ubi() -> io::Result<()> {
         ));
     }
 
-    handle_files(client_dir)?;
+    let shared = build_shared()?;
+    handle_files(client_dir, &shared)?;
 
     Ok(())
 }
 
+/// Transpiles the modules of lib/ into `src/shared`, the `shared` module of
+/// the project route files import from with `$lib/...`. A TypeScript module
+/// is lowered after the ones it imports, so it knows their types.
+fn build_shared() -> io::Result<HashMap<String, ts::Exports>> {
+    let lib_dir = Path::new("./.project_build/lib");
+    let shared_dir = Path::new("./.project_build/src/shared");
+    fs::create_dir_all(shared_dir)?;
+
+    let mut shared: HashMap<String, ts::Exports> = HashMap::new();
+    let mut pending = Vec::new();
+    if lib_dir.exists() {
+        for entry in WalkDir::new(lib_dir).sort_by_file_name() {
+            let path = entry?.into_path();
+            let Some(extension) = path.extension().and_then(|ext| ext.to_str()) else {
+                continue;
+            };
+            if !matches!(extension, "ts" | "py") {
+                continue;
+            }
+
+            let relative = path.strip_prefix(lib_dir).unwrap().with_extension("");
+            let relative = relative.to_str().unwrap().to_string();
+            let module = ts::shared_module(&relative);
+            let out = shared_dir.join(format!("{}.rs", module));
+            if shared.contains_key(&module) || pending.iter().any(|(m, _, _, _)| *m == module) {
+                return Err(io::Error::other(format!(
+                    "lib/{} is both a .ts and a .py module",
+                    relative
+                )));
+            }
+
+            if extension == "py" {
+                convert_py_to_rust(path.to_str().unwrap(), out.to_str().unwrap())
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                shared.insert(module, ts::Exports::opaque());
+            } else {
+                let source = fs::read_to_string(&path)?;
+                pending.push((module, path, out, source));
+            }
+        }
+    }
+
+    while !pending.is_empty() {
+        // A module is ready once the modules of lib/ it imports are.
+        let ready = pending.iter().position(|(_, _, _, source)| {
+            ts::imports(source).iter().all(|import| {
+                let import = ts::shared_module(import);
+                shared.contains_key(&import) || !pending.iter().any(|(m, _, _, _)| *m == import)
+            })
+        });
+        let Some(ready) = ready else {
+            let modules: Vec<&str> = pending.iter().map(|(m, _, _, _)| m.as_str()).collect();
+            return Err(io::Error::other(format!(
+                "the modules of lib/ import each other in a cycle: {}",
+                modules.join(", ")
+            )));
+        };
+
+        let (module, path, out, source) = pending.remove(ready);
+        let file_name = path.to_str().unwrap().trim_start_matches("./.project_build/");
+        let lowered = ts::lower_library(file_name, &source, &shared)
+            .map_err(io::Error::other)?;
+        fs::write(&out, lowered.rust)?;
+        SourceMap {
+            source: file_name.to_string(),
+            lines: lowered.lines,
+        }
+        .write(&out)?;
+        shared.insert(module, lowered.exports);
+    }
+
+    let mut modules: Vec<&String> = shared.keys().collect();
+    modules.sort();
+    let mod_rs: String = modules.iter().map(|module| format!("pub mod {};\n", module)).collect();
+    fs::write(shared_dir.join("mod.rs"), mod_rs)?;
+
+    Ok(shared)
+}
+
 fn cek_file(path_str: &str) -> bool {
     let path = Path::new(path_str);
     let nama_sesuai = path.file_stem().and_then(|stem| stem.to_str()) == Some("server");
@@ -72,13 +168,13 @@ fn cek_file(path_str: &str) -> bool {
     nama_sesuai && ekstensi_sesuai
 }
 
-fn handle_files(dir: &Path) -> io::Result<()> {
+fn handle_files(dir: &Path, shared: &HashMap<String, ts::Exports>) -> io::Result<()> {
     let entries = fs::read_dir(dir)?;
 
     for entry in entries {
         let path = entry?.path();
         if path.is_dir() {
-            handle_files(Path::new(&path.to_str().unwrap()))?;
+            handle_files(Path::new(&path.to_str().unwrap()), shared)?;
         } else if path.file_name().and_then(|file_name| file_name.to_str()) == Some("ui.ubi") {
             let js_path = Path::new("./.project_build/build").join(
                 path.strip_prefix("./.project_build/routes")
@@ -86,9 +182,11 @@ fn handle_files(dir: &Path) -> io::Result<()> {
                     .to_str()
                     .unwrap(),
             );
-            let mut content = resolve_imports(&path)?;
+            let source = resolve_imports(&path)?;
             fs::create_dir_all(js_path.parent().unwrap())?;
-            content = convert_ubi(&content, &path).expect("failed to compile");
+            let file_name = path.to_str().unwrap().trim_start_matches("./.project_build/");
+            let content = ubi::compile(file_name, &source)
+                .map_err(io::Error::other)?;
             fs::write(js_path.with_extension("html"), &content)?;
 
             let main = import_main(&content)?;
@@ -103,31 +201,32 @@ fn handle_files(dir: &Path) -> io::Result<()> {
             if !server_path.exists() {
                 fs::create_dir_all(server_path).unwrap();
             }
-            let file_rust_path = path.with_extension("rs");
-            let dest_path = format!(
-                "{}/{}",
-                &server_path.to_str().unwrap(),
-                &file_rust_path
-                    .strip_prefix("./.project_build/routes")
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-            );
-            let dest_relative = Path::new(&dest_path)
+            let segments: Vec<&str> = path
                 .parent()
                 .unwrap()
-                .strip_prefix("./.project_build/src")
-                .unwrap();
+                .strip_prefix("./.project_build/routes")
+                .unwrap()
+                .iter()
+                .map(|segment| segment.to_str().unwrap())
+                .collect();
+            let route = format!("/api/{}", segments.join("/"));
             let tes = format!(
                 "./.project_build/src/server/{}.rs",
-                dest_relative
-                    .to_str()
-                    .unwrap()
-                    .replace("/", "_")
-                    .replace("server", "api")
+                std::iter::once("api").chain(segments.iter().copied()).collect::<Vec<_>>().join("_")
             );
 
-            process_file(path.to_str().unwrap(), &tes).expect("Compilation failed");
+            process_file(path.to_str().unwrap(), &tes, shared)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+
+            // generate_mod_rs reads the route back from this line.
+            if Path::new(&tes).exists() {
+                let generated = fs::read_to_string(&tes)?;
+                fs::write(&tes, format!("// route: {}\n{}", route.trim_end_matches('/'), generated))?;
+                if let Some(mut map) = SourceMap::read(Path::new(&tes)) {
+                    map.lines.insert(0, 0);
+                    map.write(Path::new(&tes))?;
+                }
+            }
         }
     }
 
@@ -151,237 +250,12 @@ fn resolve_imports(path: &Path) -> io::Result<String> {
         content = content.replace(&pattern, &replacement);
     }
 
-    handle_anchors(&mut content)?;
-
     Ok(content)
 }
 
-fn handle_if(input: &str, js_input: &str) -> (String, String) {
-    let re = Regex::new(r"(?i)<if\s+([^>]+)>|</if>").unwrap();
-
-    let mut stack: Vec<(String, usize, String)> = Vec::new();
-    let mut output = String::new();
-    let mut js = String::new();
-    let mut last_pos = 0;
-
-    let variables = get_variables(js_input, ":[1] :[2] = new Signal", ":[2]", ".js").unwrap();
-
-    for cap in re.captures_iter(input) {
-        let full_match = cap.get(0).unwrap();
-        let start = full_match.start();
-
-        if stack.is_empty() {
-            output.push_str(&input[last_pos..start]);
-        } else {
-            let (_, _, text) = stack.last_mut().unwrap();
-            *text += &input[last_pos..start];
-        }
-
-        if let Some(cond) = cap.get(1) {
-            let condition = cond.as_str().trim().to_string();
-            stack.push((condition, start, String::new()));
-        } else if let Some((condition, pos, text)) = stack.pop() {
-            let content = text.trim().to_string();
-            let id = format!("a{}", Uuid::new_v4().to_string().replace("-", "_"));
-            let converted = format!(
-                r#"
-<div id='{id}'>{content}</div>
-"#
-            );
-            for var in variables.iter() {
-                let var2 = format!("{var}.get()");
-                if condition.contains(var2.as_str()) {
-                    js = format!(
-                        r#"
-    let {id} = document.getElementById('{id}');
-    let {id}_prev_display = window.getComputedStyle({id}).display;
-    if ({id}_prev_display === "none") {{
-        {id}_prev_display = "block";
-    }}
-
-    effect(() => {{
-        if ({condition}) {{
-        {id}.style.display = {id}_prev_display;
-    }} else {{
-        {id}.style.display = "none";
-            }}
-    }});
-    "#
-                    );
-                } else {
-                    js = format!(
-                        r#"
-    let {id} = document.getElementById('{id}');
-    let {id}_prev_display = window.getComputedStyle({id}).display;
-    if ({id}_prev_display === "none") {{
-        {id}_prev_display = "block";
-    }}
-
-    if ({condition}) {{
-        {id}.style.display = {id}_prev_display;
-    }} else {{
-        {id}.style.display = "none";
-            }}
-
-    "#
-                    );
-                }
-            }
-
-            if let Some(parent) = stack.last_mut() {
-                parent.2 += &converted;
-            } else {
-                output.push_str(&converted);
-            }
-        }
-
-        last_pos = full_match.end();
-    }
-
-    output.push_str(&input[last_pos..]);
-    (output, js)
-}
-
-fn handle_anchors(content: &mut String) -> io::Result<()> {
-    let re = regex::Regex::new(r#"<a\s+[^>]*href\s*=\s*\"([^\"]*)\"[^>]*>"#).unwrap();
-    let mut result = String::new();
-    let mut last_pos = 0;
-
-    for captures in re.captures_iter(content) {
-        let start = captures.get(0).unwrap().start();
-        let end = captures.get(0).unwrap().end();
-
-        result.push_str(&content[last_pos..start]);
-
-        let mut href = captures.get(1).unwrap().as_str().to_string();
-        href = href.replace(" ", "");
-
-        if href.starts_with("/") {
-            let on_click = r#" onClick="handleNavigation(event)""#;
-            let replacement = format!(r#"<a href="{}"{}>"#, href, on_click);
-            result.push_str(&replacement);
-        }
-
-        last_pos = end;
-    }
-
-    result.push_str(&content[last_pos..]);
-    *content = result;
-
-    Ok(())
-}
-
-fn handle_for(input: &str, js_input: &str) -> (String, String) {
-    let re = Regex::new(r"(?i)<for\s+([^>]+)>|</for>").unwrap();
-
-    let mut stack: Vec<(String, usize, String)> = Vec::new();
-    let mut output = String::new();
-    let mut js = String::new();
-    let mut last_pos = 0;
-    let mut isi_for: Vec<String> = Vec::new();
-
-    let variables = get_variables(js_input, ":[1] :[2] = new Signal", ":[2]", ".js").unwrap();
-
-    for cap in re.captures_iter(input) {
-        let full_match = cap.get(0).unwrap();
-        let start = full_match.start();
-
-        if stack.is_empty() {
-            output.push_str(&input[last_pos..start]);
-        } else {
-            let (_, _, text) = stack.last_mut().unwrap();
-            *text += &input[last_pos..start];
-        }
-
-        if let Some(cond) = cap.get(1) {
-            let condition = cond.as_str().trim().to_string();
-            stack.push((condition, start, String::new()));
-        } else if let Some((condition, pos, text)) = stack.pop() {
-            let mut content = text.trim().to_string();
-            content = convert_general(&content, "{:[1]}", "${:[1]}", ".html").unwrap();
-            isi_for.push(content.clone());
-
-            let id = format!("a{}", Uuid::new_v4().to_string().replace("-", "_"));
-
-            let converted = format!(
-                r#"
-<div id='{id}'></ul>
-"#
-            );
-
-            let mut js_code = String::new();
-            if condition.contains(" in ") {
-                let parts: Vec<&str> = condition.split(" in ").collect();
-                let item_var = parts[0].trim();
-                let array_var = parts[1].trim();
-
-                for var in variables.iter() {
-                    if condition.contains(format!("{var}.get()").as_str()) {
-                        js_code = format!(
-                            r#"
-let {id} = document.getElementById('{id}');
-function render_{id}() {{
-    {id}.innerHTML = "";
-    {array_var}.forEach(({item_var}, i) => {{
-        let div = document.createElement("div");
-        div.innerHTML = `{content}`;
-        {id}.appendChild(div);
-    }});
-}}
-effect(render_{id});
-"#
-                        )
-                    } else {
-                        js_code = format!(
-                            r#"
-let {id} = document.getElementById('{id}');
-function render_{id}() {{
-    {id}.innerHTML = "";
-    {array_var}.forEach(({item_var}, i) => {{
-        let div = document.createElement("div");
-        div.innerHTML = `{content}`;
-        {id}.appendChild(div);
-    }});
-}}
-"#
-                        )
-                    }
-                }
-            } else if condition.contains("=") {
-                let parts: Vec<&str> = condition.split("=").collect();
-                let var_name = parts[0].trim();
-                let range_value = parts[1].trim();
-
-                js_code = format!(
-                    r#"
-let {id} = document.getElementById('{id}');
-for (let {var_name} = 0; {var_name} < {range_value}; {var_name}++) {{
-    let div = document.createElement("div");
-    div.innerHTML = `{content}`;
-    {id}.appendChild(div);
-}}
-"#
-                )
-            } else {
-                js_code = "".to_string()
-            };
-
-            js.push_str(&js_code);
-
-            if let Some(parent) = stack.last_mut() {
-                parent.2 += &converted;
-            } else {
-                output.push_str(&converted);
-            }
-        }
-
-        last_pos = full_match.end();
-    }
-
-    output.push_str(&input[last_pos..]);
-
-    (output, js)
-}
+/// Handler functions a route file may define. `update` is the old name of
+/// `put` and is still accepted.
+const HANDLER_METHODS: [&str; 8] = ["get", "post", "put", "patch", "delete", "head", "options", "update"];
 
 fn ubi_path() -> PathBuf {
     let home_dir = env::var("HOME").expect("Tidak bisa mendapatkan HOME directory");
@@ -389,8 +263,8 @@ fn ubi_path() -> PathBuf {
 }
 
 fn process_conversion(
-    input_templates: &Vec<String>,
-    output_templates: &Vec<String>,
+    input_templates: &[String],
+    output_templates: &[String],
     input_file: &str,
     output_file: &str,
     matcher: &str,
@@ -429,246 +303,409 @@ fn process_conversion(
     Ok(())
 }
 
-fn convert_ts_to_rust(
-    input_file: &str,
-    out_filename: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    StdCommand::new(ubi_path().join("dp"))
-        .arg("fmt")
-        .output()
-        .expect("Compiling failed");
+/// Index of the delimiter closing the one at `open`, skipping over nested
+/// brackets and string literals.
+fn closing_delimiter(input: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for (i, c) in input[open..].char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
 
-    let mut input_templates: Vec<String> = vec![];
-    let mut output_templates: Vec<String> = vec![];
-
-    if out_filename.contains("_:") {
-        input_templates.extend(vec![
-        // get function
-        "function get(): string {:[1] return :[2]; }",
-        "function post(): string {:[1] return :[2]; }",
-        "function update(): string {:[1] return :[2]; }",
-        "function delete(): string {:[1] return :[2]; }",
-        ].into_iter().map(String::from));
-
-        output_templates.extend(vec![
-                   "pub fn get(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap<String, String>) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn post(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap<String, String>) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn update(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap<String, String>) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn delete(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap<String, String>) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-        ].into_iter().map(String::from));
-    } else {
-        input_templates.extend(vec![
-        // get function
-        "function get(): string {:[1] return :[2]; }",
-        "function post(): string {:[1] return :[2]; }",
-        "function update(): string {:[1] return :[2]; }",
-        "function delete(): string {:[1] return :[2]; }",
-        ].into_iter().map(String::from));
-
-        output_templates.extend(vec![
-                   "pub fn get(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn post(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn update(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn delete(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-        ].into_iter().map(String::from));
+        match c {
+            '"' | '\'' | '`' => quote = Some(c),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + i);
+                }
+            }
+            _ => {}
+        }
     }
 
-    let input_templates2 = vec![
-
-        // struct json
-        r#"// json
-        type :[1] = { :[2] };"#,
-        // struct
-        r#"type :[1] = { :[2] };"#,
-        // string struct
-        ":[1]: string;",
-        // number struct
-        ":[1]: number;",
-        // boolean struct
-        ":[1]: boolean",
-        // function returns string
-        "function :[1](:[2]): string {\n:[4]\n}",
-        // function
-        "function :[1](:[2]): :[3] {\n:[4]\n}",
-        // json stringify
-        "ubi.json(:[1])",
-        "ubi.req.params(:[1])",
-        // print
-        "console.log(:[1])",
-        // string literal
-        "\":[1]\"",
-        // db query
-        "let :[1]: :[2] = ubi.query(:[3].to_string())",
-        "ubi.query(:[1].to_string())",
-        // array literal
-        "= [:[1]]",
-        // array literal in argument
-        "([:[1]])",
-        // array type
-        "Array<:[1]>",
-        // array type 2
-        ": :[1][]",
-        "\"{:?}\".to_string()",
-        ": :[1] = {:[2]}",
-        "ubi.req.data",
-    ];
+    None
+}
 
-    let output_templates2 = vec![
-        // struct
-        r#"#[derive(Debug, serde::Deserialize, serde::Serialize)]
-        struct :[1] { :[2] }"#,
-        // struct
-        "struct :[1] { :[2] }",
-        // string struct
-        ":[1]: String,",
-        // number struct
-        ":[1]: i32,",
-        // boolean struct
-        ":[1]: bool",
-
-        // function returns string
-        "fn :[1](:[2]) -> String {\n:[4]\n}",
-        // function
-        "fn :[1](:[2]) -> :[3] {\n:[4]\n}",
-        // json stringify
-        "serde_json::json!(&:[1]).to_string()",
-        "req_params.get(&:[1])",
-        // print
-        "println!(\"{:?}\", :[1])",
-        // string literal
-        "\":[1]\".to_string()",
-        // db query
-        "let :[1] = db.query(:[3])?",
-        "db.query(:[1])?",
-        // array literal
-        "= vec![:[1]]",
-        // array literal in argument
-        "(vec![:[1]])",
-        // array type
-        "Vec<:[1]>",
-        // array type 2
-        ": Vec<:[1]>",
-        "\"{:?}\"",
-        " = :[1] {:[2]}",
-        "serde_json::from_slice(req.body().fill_buf().unwrap()).unwrap()",
-    ];
+/// Splits `input` on `separator` wherever it is not nested in brackets or strings.
+fn split_top_level(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in input.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
 
-    input_templates.extend(input_templates2.into_iter().map(String::from));
-    output_templates.extend(output_templates2.into_iter().map(String::from));
+        match c {
+            '"' | '\'' | '`' => quote = Some(c),
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
 
-    process_conversion(
-        &input_templates,
-        &output_templates,
-        input_file,
-        out_filename,
-        ".ts",
-    )
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Reads the `key: value` pairs of a `{ ... }` object (or Python dict) literal.
+fn object_fields(input: &str) -> Option<Vec<(String, String)>> {
+    let inner = input.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let mut fields = Vec::new();
+
+    for field in split_top_level(inner, ',') {
+        if field.trim().is_empty() {
+            continue;
+        }
+        let mut pair = split_top_level(field, ':').into_iter();
+        let key = pair.next()?.trim().trim_matches(|c| c == '"' || c == '\'');
+        let value = pair.collect::<Vec<_>>().join(":");
+        if value.trim().is_empty() {
+            return None;
+        }
+        fields.push((key.to_string(), value.trim().to_string()));
+    }
+
+    Some(fields)
+}
+
+/// Rewrites `ubi.response({ status, headers, cookies, body })` into a chain of
+/// `UbiResponse` builder calls starting at `builder`.
+fn lower_ubi_response(source: &str, builder: &str) -> Result<String, String> {
+    let mut hasil = String::new();
+    let mut rest = source;
+
+    while let Some(start) = rest.find("ubi.response(") {
+        hasil.push_str(&rest[..start]);
+        let open = start + "ubi.response".len();
+        let close = closing_delimiter(rest, open).ok_or("ubi.response( is never closed")?;
+        let args = &rest[open + 1..close];
+        let fields = object_fields(args)
+            .ok_or_else(|| format!("ubi.response expects an object literal, found `{}`", args.trim()))?;
+
+        hasil.push_str(builder);
+        for (key, value) in fields {
+            match key.as_str() {
+                "status" | "body" => hasil.push_str(&format!(".{}({})", key, value)),
+                "headers" | "cookies" => {
+                    let method = if key == "headers" { "header" } else { "cookie" };
+                    let entries = object_fields(&value)
+                        .ok_or_else(|| format!("ubi.response {} must be an object literal", key))?;
+                    for (name, value) in entries {
+                        hasil.push_str(&format!(".{}(\"{}\", {})", method, name, value));
+                    }
+                }
+                other => return Err(format!("unknown ubi.response field `{}`", other)),
+            }
+        }
+
+        rest = &rest[close + 1..];
+    }
+
+    hasil.push_str(rest);
+    Ok(hasil)
+}
+
+fn convert_ts_to_rust(
+    input_file: &str,
+    out_filename: &str,
+    shared: &HashMap<String, ts::Exports>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let source = fs::read_to_string(input_file)?;
+    let file_name = input_file.trim_start_matches("./.project_build/");
+    let lowered = ts::lower(file_name, &source, shared)?;
+    fs::write(out_filename, lowered.rust)?;
+
+    SourceMap {
+        source: file_name.to_string(),
+        lines: lowered.lines,
+    }
+    .write(Path::new(out_filename))?;
+
+    Ok(())
 }
 
 fn convert_py_to_rust(
     input_file: &str,
     out_filename: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let py2many_output = StdCommand::new(ubi_path().join("pn"))
+    // py2many has no notion of our context manager, so the block is handed to
+    // it as an `if` and turned into a closure afterwards.
+    let original = fs::read_to_string(input_file)?;
+    let transaction_re = Regex::new(r"(?m)^(\s*)with\s+ubi\.transaction\(\)\s*:").unwrap();
+    let source = transaction_re.replace_all(&original, "${1}if ubi.transaction():");
+
+    // `from lib.text import slug` is resolved here, py2many only sees an
+    // empty line in its place.
+    let import_re = Regex::new(r"(?m)^from\s+lib\.([\w.]+)\s+import\s+(.+?)\s*$").unwrap();
+    let uses: Vec<String> = import_re
+        .captures_iter(&source)
+        .map(|captures| {
+            format!(
+                "use crate::shared::{}::{{{}}};",
+                ts::shared_module(&captures[1]),
+                captures[2].trim_matches(|c| c == '(' || c == ')')
+            )
+        })
+        .collect();
+    let source = import_re.replace_all(&source, "");
+    fs::write(input_file, lower_ubi_response(&source, "ubi_response_new()")?)?;
+
+    let pn_path = ubi_path().join("pn");
+    if !pn_path.exists() {
+        return Err("py2many is not installed, run `ubi setup` to install it".into());
+    }
+
+    let py2many_output = StdCommand::new(&pn_path)
         .args(["--rust=1", input_file])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .output()?;
+        .output()
+        .map_err(|e| format!("py2many can't be started ({}), run `ubi setup` to reinstall it", e))?;
 
     if !py2many_output.status.success() {
-        return Err("compilation failed".into());
+        return Err(format!(
+            "py2many failed\n{}",
+            String::from_utf8_lossy(&py2many_output.stderr).trim_end()
+        )
+        .into());
     }
 
     let rust_file = Path::new(input_file).with_extension("rs");
-    let rust_file_path = rust_file.to_str().ok_or("Invalid path")?;
-
     if !rust_file.exists() {
         return Err("File tidak ditemukan".into());
     }
 
-    let mut input_templates: Vec<String> = vec![];
-    let mut output_templates: Vec<String> = vec![];
+    let converted = lower_py2many(&fs::read_to_string(&rust_file)?);
+    let validate_impls = python_validate_impls(&converted);
+    let rust = format!("{}\n{}", uses.join("\n"), converted);
+    fs::write(out_filename, format!("{}\n{}", rust, validate_impls))?;
 
-    if out_filename.contains("_:") {
-        input_templates.extend(vec![
-             "pub fn get() -> String {:[1] return :[2]; }",
-                    "pub fn post() -> String {:[1] return :[2]; }",
-                    "pub fn update() -> String {:[1] return :[2]; }",
-                    "pub fn delete() -> String {:[1] return :[2]; }",
-
-        ].into_iter().map(String::from));
-
-        output_templates.extend(vec![
-             "pub fn get(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn post(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn update(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn delete(db: &crate::PgConnection, req: may_minihttp::Request, req_params: &std::collections::HashMap) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
+    // The `Validate` impls come from no line in particular.
+    SourceMap {
+        source: input_file.trim_start_matches("./.project_build/").to_string(),
+        lines: source_map::python_lines(&original, &rust),
+    }
+    .write(Path::new(out_filename))?;
 
-        ].into_iter().map(String::from));
-    } else {
-        input_templates.extend(vec![
-             "pub fn get() -> String {:[1] return :[2]; }",
-                    "pub fn post() -> String {:[1] return :[2]; }",
-                    "pub fn update() -> String {:[1] return :[2]; }",
-                    "pub fn delete() -> String {:[1] return :[2]; }",
+    Ok(())
+}
 
-        ].into_iter().map(String::from));
+/// Turns the Rust py2many wrote for a server.py into a route module, the
+/// `ubi` API lowered to the runtime's like `ts::lower` does for server.ts.
+fn lower_py2many(rust: &str) -> String {
+    // py2many writes a crate of its own, what only a crate root takes goes.
+    let crate_re = Regex::new(r"(?m)^(//!.*|#!\[.*\]|extern crate .*;|use anyhow::.*;)\n").unwrap();
+    let derive_re = Regex::new(r"(?m)^(#\[derive\(.*\)\]\n)?pub struct ").unwrap();
+    let rust = crate_re.replace_all(rust, "");
+    let mut rust = derive_re
+        .replace_all(&rust, "#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]\npub struct ")
+        .into_owned();
+
+    // `with ubi.transaction():` came in as `if ubi.transaction():`.
+    rust = rewrite::replace_all_with(&rust, "if ubi.transaction() {:[body]}", |holes| {
+        let body = holes["body"].trim_end();
+        let indent = holes["body"][body.len()..].rsplit('\n').next().unwrap_or_default();
+        format!("db.transaction(|| {{{body}\n{indent}    Ok(())\n{indent}}})?;")
+    });
+
+    for (template, replacement) in python_templates() {
+        if let Some(template) = template.strip_suffix(", vec![:[2]])") {
+            // Bound by value like in TypeScript, a parameter mustn't be moved
+            // out of the handler.
+            rust = rewrite::replace_all_with(&rust, &format!("{}, vec![:[2]])", template), |holes| {
+                let params: Vec<String> = split_top_level(holes["2"], ',')
+                    .into_iter()
+                    .map(str::trim)
+                    .filter(|param| !param.is_empty())
+                    .map(|param| match param.chars().next() {
+                        Some('"' | '0'..='9' | '-') => param.to_string(),
+                        _ if param == "None" => param.to_string(),
+                        _ => format!("{}.clone()", param),
+                    })
+                    .collect();
+                replacement.replace(":[1]", holes["1"]).replace(":[2]", &params.join(", "))
+            });
+        } else {
+            rust = rewrite::replace_all(&rust, &template, &replacement);
+        }
+    }
 
-                output_templates.extend(vec![
-             "pub fn get(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn post(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn update(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
-                    "pub fn delete(db: &crate::PgConnection, req: may_minihttp::Request) -> Result<String, may_postgres::Error> {\n:[1]\n return Ok(:[2]); }",
+    for method in HANDLER_METHODS {
+        // `-> str` for ubi.json, `-> Response` for ubi.response, as in TypeScript.
+        for returns in ["String", "Response"] {
+            rust = rewrite::replace_all_with(&rust, &format!("pub fn {method}() -> {returns} {{:[body]}}"), |holes| {
+                let body = rewrite::replace_all(holes["body"], "return :[1];", "return Ok(crate::UbiResponse::from(:[1]));");
+                format!("pub fn {method}(db: &crate::PgConnection, req: crate::UbiRequest) -> Result<crate::UbiResponse, crate::UbiError> {{{body}}}")
+            });
+        }
+    }
 
-        ].into_iter().map(String::from));
+    rust
+}
 
+/// The rewrites of `lower_py2many`, in order: a typed form before the one
+/// that takes any type.
+fn python_templates() -> Vec<(String, String)> {
+    let mut templates: Vec<(String, String)> = [
+        ("ubi_response_new()", "crate::UbiResponse::new()"),
+        ("&str", "String"),
+        ("ubi.json(:[1])", "serde_json::json!(&:[1]).to_string()"),
+    ]
+    .into_iter()
+    .map(|(template, replacement)| (template.to_string(), replacement.to_string()))
+    .collect();
+
+    // Request values are owned, a missing one reads as "" or 0 when the
+    // variable isn't `Optional`, like in TypeScript.
+    for accessor in ["params", "header", "cookie"] {
+        templates.push((
+            format!(": Option<String> = ubi.req.{accessor}(:[1])"),
+            format!(": Option<String> = req.{accessor}(&:[1]).cloned()"),
+        ));
+        templates.push((
+            format!(": String = ubi.req.{accessor}(:[1])"),
+            format!(": String = req.{accessor}(&:[1]).cloned().unwrap_or_default()"),
+        ));
+        templates.push((
+            format!("ubi.req.{accessor}(:[1])"),
+            format!("req.{accessor}(&:[1]).cloned()"),
+        ));
     }
 
-    let input_templates2 = [
-        "&str",
-        "ubi.json(:[1])",
-        "ubi.req.params(:[1])",
-        ": String = None",
-        "impl :[1] { :[2] }",
-        ": :[1] = ubi.query(:[2])",
-        "ubi.query(:[2])",
-        "ubi.req.data",
-        "#json",
+    let rest = [
+        (": Option<:[[t]]> = ubi.req.query(:[1])", ": Option<:[[t]]> = req.query_as::<:[[t]]>(&:[1])?"),
+        (": Vec<:[[t]]> = ubi.req.query_all(:[1])", ": Vec<:[[t]]> = req.query_all_as::<:[[t]]>(&:[1])?"),
+        (": :[[t]] = ubi.req.query(:[1])", ": :[[t]] = req.query_as::<:[[t]]>(&:[1])?.unwrap_or_default()"),
+        ("ubi.req.query_all(:[1])", "req.query_all(&:[1]).into_iter().cloned().collect::<Vec<_>>()"),
+        ("ubi.req.query(:[1])", "req.query(&:[1]).cloned()"),
+        ("ubi.req.method", "req.method().to_string()"),
+        ("ubi.req.path", "req.path().to_string()"),
+        (": String = ubi.req.ip", ": String = req.ip().map(str::to_string).unwrap_or_default()"),
+        ("ubi.req.ip", "req.ip().map(str::to_string)"),
+        (": String = None", ": String = String::new()"),
+        ("impl :[1] { :[2] }", ""),
+        // ubi.query always answers JSON rows, the declared type only
+        // documents their shape.
+        (": :[[t]] = ubi.query(:[1], vec![:[2]])", " = db.query(:[1], sql_params![:[2]])?"),
+        ("ubi.query(:[1], vec![:[2]])", "db.query(:[1], sql_params![:[2]])?"),
+        (": :[[t]] = ubi.query(:[1])", " = db.query(:[1], &[])?"),
+        ("ubi.query(:[1])", "db.query(:[1], &[])?"),
+        ("ubi.req.data", "req.data()?"),
     ];
+    templates.extend(rest.into_iter().map(|(template, replacement)| (template.to_string(), replacement.to_string())));
 
-    let output_templates2 = vec![
-        "String",
-        "serde_json::json!(:[1]).to_string()",
-        "req_params.get(&:[1])",
-        ": String = String::new()",
-        "",
-        " = db.query(:[2])?",
-        "db.query(:[2])?",
-        "serde_json::from_slice(req.body().fill_buf().unwrap()).unwrap()",
-        "#[derive(Debug, serde::Serialize, serde::Deserialize)]",
-    ];
+    templates
+}
 
-    input_templates.extend(input_templates2.into_iter().map(String::from));
-    output_templates.extend(output_templates2.into_iter().map(String::from));
+/// `Validate` impls for the structs py2many made of the classes of a Python
+/// route, so `ubi.req.data` checks the body against them like it does in
+/// TypeScript. Fields of types the runtime can't check are only required.
+fn python_validate_impls(rust: &str) -> String {
+    let Ok(file) = syn::parse_file(rust) else {
+        return String::new();
+    };
+    let structs: Vec<&syn::ItemStruct> = file
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            syn::Item::Struct(item) => Some(item),
+            _ => None,
+        })
+        .collect();
+    let names: Vec<String> = structs.iter().map(|item| item.ident.to_string()).collect();
 
-    process_conversion(
-        &input_templates,
-        &output_templates,
-        rust_file_path,
-        out_filename,
-        ".rs",
-    )
+    let mut hasil = String::new();
+    for item in structs {
+        let syn::Fields::Named(fields) = &item.fields else {
+            continue;
+        };
+        hasil.push_str(&format!("impl crate::Validate for {} {{\n", item.ident));
+        hasil.push_str("    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {\n");
+        hasil.push_str("        if let Some(object) = crate::validate_object(value, path, errors) {\n");
+        for field in &fields.named {
+            let Some(name) = &field.ident else {
+                continue;
+            };
+            let field_type = validate_type(&field.ty, &names).unwrap_or_else(|| "serde_json::Value".to_string());
+            hasil.push_str(&format!(
+                "            crate::validate_field::<{}>(object, path, \"{}\", errors);\n",
+                field_type, name
+            ));
+        }
+        hasil.push_str("        }\n    }\n}\n\n");
+    }
+
+    hasil
+}
+
+/// The type of a field as `validate_field` takes it, when the runtime has a
+/// `Validate` impl for it.
+fn validate_type(ty: &syn::Type, structs: &[String]) -> Option<String> {
+    let syn::Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    let name = segment.ident.to_string();
+    let arguments: Vec<String> = match &segment.arguments {
+        syn::PathArguments::None => Vec::new(),
+        syn::PathArguments::AngleBracketed(arguments) => arguments
+            .args
+            .iter()
+            .map(|argument| match argument {
+                // An `Option` of something unknown is still optional.
+                syn::GenericArgument::Type(ty) => {
+                    validate_type(ty, structs).unwrap_or_else(|| "serde_json::Value".to_string())
+                }
+                _ => "serde_json::Value".to_string(),
+            })
+            .collect(),
+        syn::PathArguments::Parenthesized(_) => return None,
+    };
+
+    match (name.as_str(), arguments.as_slice()) {
+        ("String" | "bool" | "i32" | "i64" | "f64" | "Value", []) => {
+            Some(if name == "Value" { "serde_json::Value".to_string() } else { name })
+        }
+        ("Vec" | "Option", [item]) => Some(format!("{}<{}>", name, item)),
+        ("HashMap", [key, value]) if key == "String" => {
+            Some(format!("std::collections::HashMap<String, {}>", value))
+        }
+        (name, []) if structs.iter().any(|s| s == name) => Some(name.to_string()),
+        _ => None,
+    }
 }
 
 fn convert_ts_to_sql(
     input_file: &str,
     out_filename: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    StdCommand::new(ubi_path().join("dp"))
-        .arg("fmt")
-        .output()
-        .expect("Compiling failed");
-
     let input_templates: Vec<String> = vec![
         "type :[1] = { :[2] }",
         ":[1]: :[2]; // :[3]",
@@ -700,206 +737,15 @@ fn convert_ts_to_sql(
 }
 
 
-fn convert_general(
-    input: &str,
-    matcher: &str,
-    rewriter: &str,
-    extension: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut process = StdCommand::new(ubi_path().join("cb"))
-        .args([
-            matcher, rewriter, "-stdin", "-stdout", "-matcher", extension,
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    if let Some(mut stdin) = process.stdin.take() {
-        stdin.write_all(input.as_bytes())?;
-    }
-
-    let output = process.wait_with_output()?;
-
-    if !output.status.success() {
-        return Err("Formatter failed".into());
-    }
-
-    let formatted_output = String::from_utf8(output.stdout)?.replace("\n", "");
-    Ok(formatted_output)
-}
-
-fn get_html(
-    input_path: &PathBuf,
-    matcher: &str,
-    rewriter: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let input_text = fs::read_to_string(input_path)?;
-
-    let mut process = StdCommand::new(ubi_path().join("cb"))
-        .args([matcher, rewriter, "-stdin", "-stdout", "-matcher", ".html"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    if let Some(mut stdin) = process.stdin.take() {
-        stdin.write_all(input_text.as_bytes())?;
-    }
-
-    let output = process.wait_with_output()?;
-
-    if !output.status.success() {
-        return Err("Formatter failed".into());
-    }
-
-    let formatted_output = String::from_utf8(output.stdout)?.replace("\n", "");
-
-    Ok(formatted_output)
-}
-
-fn split_html_js(input: &str) -> (String, String) {
-    let script_re = Regex::new(r#"(?s)<script\b[^>]*>(.*?)</script>"#).unwrap();
-    let mut script_content = String::new();
-    let mut html_content = input.to_string();
-
-    for script_capture in script_re.captures_iter(input) {
-        if let Some(script_part) = script_capture.get(1) {
-            script_content.push_str(script_part.as_str());
-        }
-    }
-
-    html_content = script_re.replace_all(&html_content, "").to_string();
-
-    (html_content, script_content)
-}
-
-fn get_variables(
-    input: &str,
-    matcher: &str,
-    rewriter: &str,
-    extension: &str,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut extract_process = StdCommand::new(ubi_path().join("cb"))
-        .args([
-            matcher,
-            rewriter,
-            "-stdin",
-            "-stdout",
-            "-matcher",
-            extension,
-            "-newline-separated",
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    if let Some(mut stdin) = extract_process.stdin.take() {
-        stdin.write_all(input.as_bytes())?;
-    }
-
-    let extract_output = extract_process.wait_with_output()?;
-    if !extract_output.status.success() {
-        return Err("Extraction failed".into());
-    }
-
-    let extracted_vars: Vec<String> = String::from_utf8(extract_output.stdout)?
-        .lines()
-        .map(|s| s.trim().to_string())
-        .collect();
-
-    Ok(extracted_vars)
-}
-
-fn process_variables(
-    input: &str,
-    matcher: &str,
-    rewriter: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut transform_process = StdCommand::new(ubi_path().join("cb"))
-        .args([matcher, rewriter, "-stdin", "-stdout", "-matcher", ".html"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()?;
-
-    if let Some(mut stdin) = transform_process.stdin.take() {
-        stdin.write_all(input.as_bytes())?;
-    }
-
-    let transform_output = transform_process.wait_with_output()?;
-    if !transform_output.status.success() {
-        return Err("Formatter failed".into());
-    }
-
-    let hasil_transformasi = String::from_utf8(transform_output.stdout)?;
-    Ok(hasil_transformasi)
-}
-
-fn convert_ubi(input: &str, input_path: &PathBuf) -> Result<String, Box<dyn std::error::Error>> {
-    let (mut html, raw_js) = split_html_js(input);
-
-    let tmp_ts_file_path = "./.project_build/tmp.ts";
-    let mut tmp_ts_file = fs::File::create(tmp_ts_file_path)?;
-    tmp_ts_file.write_all(&raw_js.into_bytes())?;
-
-    StdCommand::new(ubi_path().join("dp"))
-        .arg("fmt")
-        .current_dir("./.project_build")
-        .output()
-        .expect("Compiling failed");
-
-    let js = fs::read_to_string(tmp_ts_file_path)?;
-    fs::remove_file(tmp_ts_file_path)?;
-
-    let mut js_for = String::new();
-    (html, js_for) = handle_for(&html, &js);
-
-    let vars = get_variables(&html, "{:[1]}", ":[1]", ".html")?;
-    let mut html_hasil = process_variables(&html, "{:[1]}", "<p class=':[1]'></p>")?;
-
-    let mut js_new = String::new();
-
-    for var in vars.iter() {
-        if js.contains(format!("{} = new Signal", var).as_str()) {
-            let teks = format!("effect(() => document.querySelectorAll('.{}').forEach(el => el.textContent = {}.get()));", var, var);
-            if !js_new.contains(&teks) {
-                js_new += &teks;
-            }
-        } else {
-            let teks = format!(
-                "document.querySelectorAll('.{}').forEach(el => el.textContent = {});",
-                var, var
-            );
-            if !js_new.contains(&teks) {
-                js_new += &teks;
-            }
-        }
-    }
-
-    let mut js_if = String::new();
-    (html_hasil, js_if) = handle_if(&html_hasil, &js);
-
-    html_hasil = html_hasil + "<script>" + &js + &js_new + &js_if + &js_for + "</script>";
-
-    let input_templates = ["{:[1]}"];
-
-    let output_templates = ["<p id=\":[1]\"></p>
-        <script>
-            document.getElementById(\":[1]\").textContent = :[1];
-        </script>"];
-
-    // process_conversion(&input_templates, &output_templates, input_file, out_filename, ".js")
-
-    Ok(html_hasil)
-}
-
-fn process_file(input_file: &str, out_filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn process_file(
+    input_file: &str,
+    out_filename: &str,
+    shared: &HashMap<String, ts::Exports>,
+) -> Result<(), Box<dyn std::error::Error>> {
     if let Some(extension) = Path::new(input_file).extension() {
         match extension.to_str() {
             Some("ts") => {
-                convert_ts_to_rust(input_file, out_filename)?;
+                convert_ts_to_rust(input_file, out_filename, shared)?;
             }
             Some("py") => {
                 convert_py_to_rust(input_file, out_filename)?;
@@ -912,30 +758,10 @@ fn process_file(input_file: &str, out_filename: &str) -> Result<(), Box<dyn std:
     Ok(())
 }
 
-fn process_folder(folder_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    for entry in WalkDir::new(folder_path)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.path().is_file())
-    {
-        if let Some(filename) = entry.path().file_name() {
-            if filename == "server.ts" || filename == "server.py" {
-                if let Some(path_str) = entry.path().to_str() {
-                    //process_file(path_str)?;
-                }
-            }
-        }
-    }
-    Ok(())
-}
-
+/// The page of a route, its component in the shell of libs/index.html. The
+/// local links of the component are wired by the .ubi compiler.
 fn import_main(new_code: &str) -> io::Result<String> {
-    let mut content = INDEX_HTML;
-    let binding = content.replace("<ubi:main>", new_code);
-    content = &binding;
-    let mut content_string = content.to_string();
-    handle_anchors(&mut content_string)?;
-    Ok(content_string)
+    Ok(INDEX_HTML.replace("<ubi:main>", new_code))
 }
 
 fn init_ubi(project_name: &str) -> io::Result<()> {
@@ -968,70 +794,237 @@ fn init_ubi(project_name: &str) -> io::Result<()> {
     Ok(())
 }
 
-fn generate_mod_rs(server_dir: &str) {
+/// A top-level function of a generated route file.
+struct RouteFn {
+    name: String,
+    /// Takes the `db: &crate::PgConnection` argument the handler templates emit.
+    handler_shaped: bool,
+}
+
+fn route_functions(file: &str) -> Vec<RouteFn> {
+    match syn::parse_file(file) {
+        Ok(ast) => ast
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                syn::Item::Fn(f) => Some(RouteFn {
+                    name: f.sig.ident.to_string(),
+                    handler_shaped: matches!(f.vis, syn::Visibility::Public(_))
+                        && matches!(
+                            f.sig.inputs.first(),
+                            Some(syn::FnArg::Typed(arg)) if matches!(&*arg.pat, syn::Pat::Ident(p) if p.ident == "db")
+                        ),
+                }),
+                _ => None,
+            })
+            .collect(),
+        // The generated code doesn't parse, cargo will point at the problem
+        // later, so only pick up what clearly is a handler.
+        Err(_) => Regex::new(r"(?m)^\s*pub fn\s+(\w+)\s*\(\s*db\s*:")
+            .unwrap()
+            .captures_iter(file)
+            .map(|c| RouteFn {
+                name: c[1].to_string(),
+                handler_shaped: true,
+            })
+            .collect(),
+    }
+}
+
+/// Maps every handler of a route file to the HTTP method it serves.
+fn route_handlers(route: &str, file: &str) -> Result<Vec<(String, String)>, String> {
+    let mut handlers: Vec<(String, String)> = Vec::new();
+
+    for function in route_functions(file) {
+        let is_method = HANDLER_METHODS.contains(&function.name.as_str());
+        if !function.handler_shaped {
+            if is_method {
+                return Err(format!(
+                    "{}: `{}` is named like a handler but has the wrong signature, handlers take no arguments and return string or Response",
+                    route, function.name
+                ));
+            }
+            continue;
+        }
+        if !is_method {
+            return Err(format!(
+                "{}: unknown handler `{}`, expected one of {}",
+                route,
+                function.name,
+                HANDLER_METHODS.join(", ")
+            ));
+        }
+
+        let method = match function.name.as_str() {
+            "update" => "put",
+            name => name,
+        };
+        if let Some((_, previous)) = handlers.iter().find(|(m, _)| m == method) {
+            return Err(format!(
+                "{}: `{}` and `{}` both handle {}",
+                route,
+                previous,
+                function.name,
+                method.to_uppercase()
+            ));
+        }
+        handlers.push((method.to_string(), function.name));
+    }
+
+    Ok(handlers)
+}
+
+/// Node of the route trie `generate_mod_rs` compiles into server/mod.rs.
+#[derive(Default)]
+struct RouteTrieNode {
+    statics: std::collections::BTreeMap<String, usize>,
+    param: Option<(String, usize)>,
+    wildcard: Option<(String, usize)>,
+    /// (method, handler expression)
+    handlers: Vec<(String, String)>,
+}
+
+struct RouteTrie {
+    nodes: Vec<RouteTrieNode>,
+}
+
+impl RouteTrie {
+    fn new() -> Self {
+        RouteTrie {
+            nodes: vec![RouteTrieNode::default()],
+        }
+    }
+
+    fn child(&mut self, parent: usize, segment: &str, route: &str, last: bool) -> Result<usize, String> {
+        let next = self.nodes.len();
+
+        if let Some(name) = segment.strip_prefix('*') {
+            if !last {
+                return Err(format!("{}: the catch-all `{}` must be the last segment", route, segment));
+            }
+            return match &self.nodes[parent].wildcard {
+                Some((existing, _)) if existing != name => Err(format!(
+                    "{}: catch-all `*{}` conflicts with `*{}` of another route",
+                    route, name, existing
+                )),
+                Some((_, index)) => Ok(*index),
+                None => {
+                    self.nodes[parent].wildcard = Some((name.to_string(), next));
+                    self.nodes.push(RouteTrieNode::default());
+                    Ok(next)
+                }
+            };
+        }
+
+        if let Some(name) = segment.strip_prefix(':') {
+            return match &self.nodes[parent].param {
+                Some((existing, _)) if existing != name => Err(format!(
+                    "{}: parameter `:{}` conflicts with `:{}` of another route",
+                    route, name, existing
+                )),
+                Some((_, index)) => Ok(*index),
+                None => {
+                    self.nodes[parent].param = Some((name.to_string(), next));
+                    self.nodes.push(RouteTrieNode::default());
+                    Ok(next)
+                }
+            };
+        }
+
+        if let Some(index) = self.nodes[parent].statics.get(segment) {
+            return Ok(*index);
+        }
+        self.nodes[parent].statics.insert(segment.to_string(), next);
+        self.nodes.push(RouteTrieNode::default());
+        Ok(next)
+    }
+
+    fn insert(&mut self, route: &str, method: &str, handler: String) -> Result<(), String> {
+        let segments: Vec<&str> = route.split('/').filter(|s| !s.is_empty()).collect();
+        let mut node = 0;
+        for (i, segment) in segments.iter().enumerate() {
+            node = self.child(node, segment, route, i + 1 == segments.len())?;
+        }
+
+        if self.nodes[node].handlers.iter().any(|(m, _)| m == method) {
+            return Err(format!("{}: {} is handled by two route files", route, method.to_uppercase()));
+        }
+        self.nodes[node].handlers.push((method.to_string(), handler));
+        Ok(())
+    }
+
+    fn to_rust(&self) -> String {
+        let mut code = String::from("pub static ROUTER: &[RouteNode] = &[\n");
+        for (index, node) in self.nodes.iter().enumerate() {
+            let statics = node
+                .statics
+                .iter()
+                .map(|(segment, child)| format!("({:?}, {})", segment, child))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let optional = |child: &Option<(String, usize)>| match child {
+                Some((name, child)) => format!("Some(({:?}, {}))", name, child),
+                None => "None".to_string(),
+            };
+            let handlers = node
+                .handlers
+                .iter()
+                .map(|(method, handler)| format!("({:?}, {})", method, handler))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            code.push_str(&format!(
+                "    // {index}\n    RouteNode {{\n        statics: &[{statics}],\n        param: {},\n        wildcard: {},\n        handlers: &[{handlers}],\n    }},\n",
+                optional(&node.param),
+                optional(&node.wildcard),
+            ));
+        }
+        code.push_str("];\n");
+        code
+    }
+}
+
+fn generate_mod_rs(server_dir: &str) -> Result<(), String> {
     let server_dir = PathBuf::from(server_dir);
     let mod_path = server_dir.join("mod.rs");
-    let mut mod_rs_file = fs::File::create(&mod_path).unwrap();
 
-    let mut routes = Vec::new();
-    let mut parameterized_routes = Vec::new();
+    let mut trie = RouteTrie::new();
     let mut modules = Vec::new();
 
-    for entry in fs::read_dir(&server_dir).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
+    let mut paths = fs::read_dir(&server_dir)
+        .map_err(|e| e.to_string())?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    paths.sort();
 
+    for path in paths {
         if path.is_file() && path.extension().unwrap_or_default() == "rs" {
             if let Some(file_name) = path.file_stem().and_then(|s| s.to_str()) {
                 if file_name != "mod" {
-                    if file_name.contains("_:") {
-                        modules.push(format!("#[path = \"{}.rs\"]\npub mod {};", file_name, file_name.replace("_:", "_")));
+                    let module: String = file_name
+                        .chars()
+                        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                        .collect();
+                    if module != file_name {
+                        modules.push(format!("#[path = \"{}.rs\"]\npub mod {};", file_name, module));
                     } else {
                         modules.push(format!("pub mod {file_name};"));
                     }
 
-                    let file = fs::read_to_string(&path).unwrap();
-
-                    if file_name.contains("_:") {
-                         let filename = file_name.replace("_:", "_");
-                        let key = file_name.replace("_", "/");
-
-                         if file.contains("pub fn get(") {
-                                                parameterized_routes.push(format!(
-                            "(\"/{key}/get\", {filename}::get as HandlerFn2)"
-                            ));
-                                            } else if file.contains("pub fn post(") {
-                                                parameterized_routes.push(format!(
-                            "(\"/{key}/post\", {filename}::post as HandlerFn2)"
-                            ));
-                                            } else if file.contains("pub fn update(") {
-                                                parameterized_routes.push(format!(
-                            "(\"/{key}/update\", {filename}::update as HandlerFn2)"
-                            ));
-                                            } else if file.contains("pub fn delete(") {
-                                                parameterized_routes.push(format!(
-                            "(\"/{key}/delete\", {filename}::delete as HandlerFn2)"
-                            ));
-                                            }
-
-                    } else {
-                         if file.contains("pub fn get(") {
-                                                routes.push(format!(
-                            "(\"/{file_name}/get\", {file_name}::get as HandlerFn)"
-                            ));
-                                            } else if file.contains("pub fn post(") {
-                                                routes.push(format!(
-                            "(\"/{file_name}/post\", {file_name}::post as HandlerFn)"
-                            ));
-                                            } else if file.contains("pub fn update(") {
-                                                routes.push(format!(
-                            "(\"/{file_name}/update\", {file_name}::update as HandlerFn)"
-                            ));
-                                            } else if file.contains("pub fn delete(") {
-                                                routes.push(format!(
-                            "(\"/{file_name}/delete\", {file_name}::delete as HandlerFn)"
-                            ));
-                                            }
+                    let file = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+                    let route = file
+                        .lines()
+                        .next()
+                        .and_then(|line| line.strip_prefix("// route: "))
+                        .map(|route| route.trim().to_string())
+                        .unwrap_or_else(|| format!("/{}", file_name.replace("_", "/")));
+                    for (method, function) in route_handlers(file_name, &file)? {
+                        trie.insert(
+                            &route,
+                            &method,
+                            format!("{module}::{function} as HandlerFn"),
+                        )?;
                     }
                 }
             }
@@ -1040,43 +1033,18 @@ fn generate_mod_rs(server_dir: &str) {
 
     let generated_code = format!(
         r#"
-use std::collections::HashMap;
-use lazy_static::lazy_static;
-use crate::PgConnection;
+use crate::{{PgConnection, RouteNode, UbiError, UbiRequest, UbiResponse}};
 
 {}
 
-pub type HandlerFn = fn(&PgConnection, may_minihttp::Request) -> Result<String, may_postgres::Error>;
-pub type HandlerFn2 = fn(&PgConnection, may_minihttp::Request, &HashMap<String, String>) -> Result<String, may_postgres::Error>;
-
-lazy_static! {{
-    pub static ref ROUTES: HashMap<&'static str, HandlerFn> = {{
-        let mut map = HashMap::new();
-        {};
-        map
-    }};
-
-    pub static ref PARAMETERIZED_ROUTES: HashMap<&'static str, HandlerFn2> = {{
-        let mut map = HashMap::new();
-        {};
-        map
-    }};
-}}
-"#,
+pub type HandlerFn = fn(&PgConnection, UbiRequest) -> Result<UbiResponse, UbiError>;
+
+{}"#,
         modules.join("\n"),
-        routes
-            .iter()
-            .map(|route| format!("map.insert{};", route))
-            .collect::<Vec<_>>()
-            .join("\n        "),
-         parameterized_routes
-            .iter()
-            .map(|route| format!("map.insert{};", route))
-            .collect::<Vec<_>>()
-            .join("\n        ")
+        trie.to_rust()
     );
 
-    mod_rs_file.write_all(&generated_code.into_bytes()).unwrap();
+    fs::write(&mod_path, generated_code).map_err(|e| e.to_string())
 }
 
 fn models_to_sql(dir: &Path) {
@@ -1153,8 +1121,6 @@ fn main() -> io::Result<()> {
         .subcommand(Command::new("init_postgres").about("Migrate PostgreSQL database"))
         .get_matches();
 
-    let mut project_name = String::new();
-
     match matches.subcommand_name() {
         Some("setup") => {
             let (distro_name, distro_id) = detect_linux_distribution().unwrap();
@@ -1169,13 +1135,17 @@ fn main() -> io::Result<()> {
 
         if copy_libraries(&lib_dir).unwrap() {
             setup_libraries(&lib_dir, &distro_id).unwrap();
+            if let Err(e) = setup_py2many(&ubi_dir, &lib_dir) {
+                println!("Setting up py2many failed, {}", e);
+                println!("Python routes can't be built until `ubi setup` succeeds, TypeScript routes are not affected");
+            }
             add_to_shell_config(&ubi_dir).unwrap();
             println!("Yuhuu Ubi environment has been setup successfully!");
         }
         },
         Some("init") => {
             if let Some(init_matches) = matches.subcommand_matches("init") {
-                project_name = init_matches
+                let project_name = init_matches
                     .get_one::<String>("project_name")
                     .unwrap()
                     .to_string();
@@ -1188,7 +1158,7 @@ fn main() -> io::Result<()> {
         },
         Some("init_postgres") => {
             let db_dir = Path::new(".project_build/db/postgres");
-            fs::create_dir_all(&db_dir)?;
+            fs::create_dir_all(db_dir)?;
             models_to_sql(Path::new("./routes"));
             println!("Created all PostgreSQL tables successfully!");
         },
@@ -1207,14 +1177,10 @@ fn main() -> io::Result<()> {
             fs::create_dir_all(&libs_build_dir).expect("Compiling failed");
             fs::create_dir_all(&final_build_dir).expect("Compiling failed");
 
-            let dprint_config_path = current_dir.join("dprint.json");
-            let mut dprint_config_file =
-                fs::File::create(&dprint_config_path).expect("Compiling failed");
-            dprint_config_file
-                .write_all(DPRINT_CONFIG.as_bytes())
-                .expect("Compiling failed");
-
-            let _ = build_ubi();
+            if let Err(e) = build_ubi() {
+                eprintln!("Build failed, {}", e);
+                std::process::exit(1);
+            }
 
             StdCommand::new("cp")
                 .arg("./config.json")
@@ -1222,7 +1188,10 @@ fn main() -> io::Result<()> {
                 .output()
                 .expect("Compiling failed");
 
-            generate_mod_rs("./.project_build/src/server");
+            if let Err(e) = generate_mod_rs("./.project_build/src/server") {
+                eprintln!("Build failed, {}", e);
+                std::process::exit(1);
+            }
 
             let cargo_path = current_dir.join("Cargo.toml");
             let mut cargo_file = fs::File::create(&cargo_path).expect("Compiling failed");
@@ -1240,13 +1209,24 @@ fn main() -> io::Result<()> {
 
             let _ = env::set_current_dir(&project_build_dir);
 
-            StdCommand::new("cargo")
+            let cargo = StdCommand::new("cargo")
                 .arg("build")
                 .arg("--release")
+                .arg("--message-format=json")
                 .current_dir(&project_build_dir)
                 .output()
                 .expect("Compiling failed");
 
+            if !cargo.status.success() {
+                let project_root = project_build_dir.parent().unwrap_or(&project_build_dir);
+                let errors = source_map::report_cargo_errors(&cargo.stdout, project_root, &project_build_dir);
+                if errors == 0 {
+                    eprintln!("{}", String::from_utf8_lossy(&cargo.stderr));
+                }
+                eprintln!("Build failed, cargo couldn't compile the project");
+                std::process::exit(1);
+            }
+
             StdCommand::new("cp")
                 .arg("-r")
                 .arg("../static")
@@ -1328,27 +1308,13 @@ fn copy_libraries(lib_dir: &str) -> Result<bool, Box<dyn std::error::Error>> {
 
     if arch == "x86_64" {
         let cb_path = Path::new(lib_dir).join("cb");
-        let pn_path = Path::new(lib_dir).join("pn");
-        let dp_path = Path::new(lib_dir).join("dp");
         let mut cb = fs::File::create(&cb_path).expect("Setup failed");
         cb.write_all(CB).expect("Setup failed");
-        let mut pn = fs::File::create(&pn_path).expect("Setup failed");
-        pn.write_all(PN).expect("Setup failed");
-        let mut dp = fs::File::create(&dp_path).expect("Setup failed");
-        dp.write_all(DP).expect("Setup failed");
 
         StdCommand::new("chmod")
             .arg("+x")
             .arg(&cb_path)
             .output()?;
-        StdCommand::new("chmod")
-            .arg("+x")
-            .arg(&pn_path)
-            .output()?;
-        StdCommand::new("chmod")
-            .arg("+x")
-            .arg(&dp_path)
-            .output()?;
 
     } else {
         println!("Sorry your architecture {} currently isn't supported by Ubi. The supported architecture is x86_64", arch);
@@ -1358,6 +1324,76 @@ fn copy_libraries(lib_dir: &str) -> Result<bool, Box<dyn std::error::Error>> {
     Ok(true)
 }
 
+/// Installs py2many in its own virtualenv under `~/.ubi/py2many` and writes
+/// `pn`, the script `convert_py_to_rust` runs, pointing at that virtualenv.
+fn setup_py2many(ubi_dir: &str, lib_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Setting up py2many...");
+
+    let venv_dir = Path::new(ubi_dir).join("py2many");
+    let python = venv_dir.join("bin/python");
+
+    if !python.exists() {
+        let venv = StdCommand::new("python3")
+            .arg("-m")
+            .arg("venv")
+            .arg(&venv_dir)
+            .output()
+            .map_err(|e| format!("python3 can't be started ({}), please install Python 3", e))?;
+        if !venv.status.success() {
+            return Err(format!(
+                "can't create the virtualenv, {}",
+                String::from_utf8_lossy(&venv.stderr).trim_end()
+            )
+            .into());
+        }
+    }
+
+    let pip = StdCommand::new(&python)
+        .args(["-m", "pip", "install", "--quiet", &format!("py2many=={}", PY2MANY_VERSION)])
+        .output()?;
+    if !pip.status.success() {
+        return Err(format!(
+            "can't install py2many {}, {}",
+            PY2MANY_VERSION,
+            String::from_utf8_lossy(&pip.stderr).trim_end()
+        )
+        .into());
+    }
+
+    let version = StdCommand::new(&python)
+        .args(["-c", "import importlib.metadata; print(importlib.metadata.version('py2many'))"])
+        .output()?;
+    let installed = String::from_utf8_lossy(&version.stdout).trim().to_string();
+    if installed != PY2MANY_VERSION {
+        return Err(format!(
+            "ubi needs py2many {} but {} has {}, remove {} and run `ubi setup` again",
+            PY2MANY_VERSION,
+            venv_dir.display(),
+            if installed.is_empty() { "none" } else { &installed },
+            venv_dir.display()
+        )
+        .into());
+    }
+
+    // Swap the shebang of the bundled script for the virtualenv's interpreter.
+    let script = String::from_utf8_lossy(PN);
+    let body = script.split_once('\n').map_or("", |(_, body)| body);
+    let pn_path = Path::new(lib_dir).join("pn");
+    fs::write(&pn_path, format!("#!{}\n{}", python.display(), body))?;
+    fs::set_permissions(&pn_path, fs::Permissions::from_mode(0o755))?;
+
+    let check = StdCommand::new(&pn_path).arg("--help").output()?;
+    if !check.status.success() {
+        return Err(format!(
+            "py2many is installed but doesn't run, {}",
+            String::from_utf8_lossy(&check.stderr).trim_end()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
 fn setup_libraries(lib_dir: &str, distro_id: &str) -> Result<(), Box<dyn std::error::Error>> {
     let pcre_source = find_pcre_library(distro_id)?;
 
@@ -1514,3 +1550,435 @@ fn add_to_shell_config(ubi_dir: &str) -> Result<(), Box<dyn std::error::Error>>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(routes: &[(&str, &str)]) -> Result<RouteTrie, String> {
+        let mut trie = RouteTrie::new();
+        for (route, method) in routes {
+            trie.insert(route, method, format!("{}_{}", route.replace(['/', ':', '*'], "_"), method))?;
+        }
+        Ok(trie)
+    }
+
+    #[test]
+    fn routes_share_their_prefixes() {
+        let trie = trie(&[
+            ("/api/users", "get"),
+            ("/api/users/:id", "get"),
+            ("/api/users/:id", "put"),
+            ("/api/users/me", "get"),
+            ("/api/files/*path", "get"),
+        ])
+        .unwrap();
+
+        let api = &trie.nodes[trie.nodes[0].statics["api"]];
+        assert_eq!(api.statics.keys().collect::<Vec<_>>(), ["files", "users"]);
+        let users = &trie.nodes[api.statics["users"]];
+        assert_eq!(users.statics.keys().collect::<Vec<_>>(), ["me"]);
+        let (name, id) = users.param.clone().unwrap();
+        assert_eq!(name, "id");
+        assert_eq!(trie.nodes[id].handlers.len(), 2);
+        let files = &trie.nodes[api.statics["files"]];
+        assert_eq!(files.wildcard.as_ref().map(|(name, _)| name.as_str()), Some("path"));
+    }
+
+    #[test]
+    fn table_lists_the_nodes_in_order() {
+        let trie = trie(&[("/api/users/:id", "get")]).unwrap();
+        assert_eq!(
+            trie.to_rust(),
+            r#"pub static ROUTER: &[RouteNode] = &[
+    // 0
+    RouteNode {
+        statics: &[("api", 1)],
+        param: None,
+        wildcard: None,
+        handlers: &[],
+    },
+    // 1
+    RouteNode {
+        statics: &[("users", 2)],
+        param: None,
+        wildcard: None,
+        handlers: &[],
+    },
+    // 2
+    RouteNode {
+        statics: &[],
+        param: Some(("id", 3)),
+        wildcard: None,
+        handlers: &[],
+    },
+    // 3
+    RouteNode {
+        statics: &[],
+        param: None,
+        wildcard: None,
+        handlers: &[("get", _api_users__id_get)],
+    },
+];
+"#
+        );
+    }
+
+    /// project/src/server is the route module of a project with one route,
+    /// so the runtime's tests build; it must be what `ubi build` writes.
+    #[test]
+    fn project_server_module_is_generated() {
+        let dir = env::temp_dir().join(format!("ubi-server-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("project/src/server/tes.rs", dir.join("tes.rs")).unwrap();
+        generate_mod_rs(dir.to_str().unwrap()).unwrap();
+        let generated = fs::read_to_string(dir.join("mod.rs")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(fs::read_to_string("project/src/server/mod.rs").unwrap(), generated);
+    }
+
+    #[test]
+    fn parameters_of_one_segment_must_have_the_same_name() {
+        let error = trie(&[("/api/users/:id", "get"), ("/api/users/:name/posts", "get")]).err();
+        assert_eq!(
+            error.as_deref(),
+            Some("/api/users/:name/posts: parameter `:name` conflicts with `:id` of another route")
+        );
+        let error = trie(&[("/api/files/*path", "get"), ("/api/files/*rest", "put")]).err();
+        assert_eq!(
+            error.as_deref(),
+            Some("/api/files/*rest: catch-all `*rest` conflicts with `*path` of another route")
+        );
+    }
+
+    #[test]
+    fn catch_all_must_be_the_last_segment() {
+        let error = trie(&[("/api/*path/raw", "get")]).err();
+        assert_eq!(
+            error.as_deref(),
+            Some("/api/*path/raw: the catch-all `*path` must be the last segment")
+        );
+    }
+
+    #[test]
+    fn method_is_handled_by_one_route_file() {
+        let error = trie(&[("/api/users", "get"), ("/api/users/", "get")]).err();
+        assert_eq!(error.as_deref(), Some("/api/users/: GET is handled by two route files"));
+    }
+
+    /// The same routes in TypeScript, in Python and as py2many writes the
+    /// Python in Rust, before `lower_py2many`.
+    const CORPUS: &[(&str, &str, &str)] = &[
+        (
+            r#"
+type Data = { tes: string; };
+
+function get(): string {
+    let id: string = ubi.req.params("id");
+    let token: string | undefined = ubi.req.header("x-token");
+    let page: number = ubi.req.query<number>("page");
+    let method: string = ubi.req.method;
+    let hasil: Data[] = ubi.query("select * from tes where id = $1 and page = $2", [id, page]);
+    return ubi.json(hasil);
+}
+"#,
+            r#"
+from typing import Optional, TypedDict
+
+class Data(TypedDict):
+    tes: str
+
+def get() -> str:
+    id: str = ubi.req.params("id")
+    token: Optional[str] = ubi.req.header("x-token")
+    page: float = ubi.req.query("page")
+    method: str = ubi.req.method
+    hasil: list[Data] = ubi.query("select * from tes where id = $1 and page = $2", [id, page])
+    return ubi.json(hasil)
+"#,
+            r#"//! ```cargo
+//! [package]
+//! edition = "2021"
+//! [dependencies]
+//! anyhow = "*"
+//! ```
+
+#![allow(clippy::needless_return)]
+#![allow(non_snake_case)]
+#![allow(unused_imports)]
+
+extern crate anyhow;
+use anyhow::Result;
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Data {
+    pub tes: String,
+}
+
+pub fn get() -> String {
+    let id: &str = ubi.req.params("id");
+    let token: Option<&str> = ubi.req.header("x-token");
+    let page: f64 = ubi.req.query("page");
+    let method: &str = ubi.req.method;
+    let hasil: Vec<Data> = ubi.query("select * from tes where id = $1 and page = $2", vec![id, page]);
+    return ubi.json(hasil);
+}
+"#,
+        ),
+        (
+            r#"
+type CreateUser = { name: string; };
+
+function post(): Response {
+    let user: CreateUser = ubi.req.data;
+    ubi.transaction(() => {
+        ubi.query("insert into users(name) values($1)", [user.name]);
+    });
+    return ubi.response({ status: 201, body: ubi.json(user) });
+}
+
+function update(): string {
+    let ip: string | undefined = ubi.req.ip;
+    return ubi.json(ip);
+}
+
+function patch(): string {
+    let tags: string[] = ubi.req.queryAll("tag");
+    ubi.query("delete from users");
+    return ubi.json(tags);
+}
+"#,
+            r#"
+from typing import Optional, TypedDict
+
+class CreateUser(TypedDict):
+    name: str
+
+def post() -> Response:
+    user: CreateUser = ubi.req.data
+    with ubi.transaction():
+        ubi.query("insert into users(name) values($1)", [user.name])
+    return ubi.response({"status": 201, "body": ubi.json(user)})
+
+def update() -> str:
+    ip: Optional[str] = ubi.req.ip
+    return ubi.json(ip)
+
+def patch() -> str:
+    tags: list[str] = ubi.req.query_all("tag")
+    ubi.query("delete from users")
+    return ubi.json(tags)
+"#,
+            r#"#![allow(clippy::needless_return)]
+#![allow(non_snake_case)]
+
+extern crate anyhow;
+use anyhow::Result;
+
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct CreateUser {
+    pub name: String,
+}
+
+pub fn post() -> Response {
+    let user: CreateUser = ubi.req.data;
+    if ubi.transaction() {
+        ubi.query("insert into users(name) values($1)", vec![user.name]);
+    }
+    return ubi_response_new().status(201).body(ubi.json(user));
+}
+
+pub fn update() -> String {
+    let ip: Option<&str> = ubi.req.ip;
+    return ubi.json(ip);
+}
+
+pub fn patch() -> String {
+    let tags: Vec<&str> = ubi.req.query_all("tag");
+    ubi.query("delete from users");
+    return ubi.json(tags);
+}
+"#,
+        ),
+    ];
+
+    /// The part of the runtime in project/src/main.rs generated handlers
+    /// use, with the same signatures, for `type_check`.
+    const HANDLER_API: &str = r#"#![allow(warnings)]
+macro_rules! sql_params {
+    (@acc [$($out:expr,)*]) => { &[$($out,)*] };
+    (@acc [$($out:expr,)*] null $(, $($rest:tt)*)?) => {
+        sql_params!(@acc [$($out,)* $crate::SqlParam::Null,] $($($rest)*)?)
+    };
+    (@acc [$($out:expr,)*] None $(, $($rest:tt)*)?) => {
+        sql_params!(@acc [$($out,)* $crate::SqlParam::Null,] $($($rest)*)?)
+    };
+    (@acc [$($out:expr,)*] $param:expr $(, $($rest:tt)*)?) => {
+        sql_params!(@acc [$($out,)* $crate::SqlParam::from($param),] $($($rest)*)?)
+    };
+    ($($params:tt)*) => { sql_params!(@acc [] $($params)*) };
+}
+pub mod serde_json {
+    pub type Map<K, V> = std::collections::HashMap<K, V>;
+    pub struct Value;
+    macro_rules! json { ($value:expr) => { { let _ = $value; String::new() } }; }
+    pub(crate) use json;
+}
+use serde_json::{Map, Value};
+pub struct UbiError;
+pub struct FieldError;
+pub struct PgConnection;
+impl PgConnection {
+    pub fn query(&self, stmt: &str, params: &[SqlParam]) -> Result<Vec<Value>, UbiError> { todo!() }
+    pub fn transaction<T>(&self, f: impl FnOnce() -> Result<T, UbiError>) -> Result<T, UbiError> { todo!() }
+}
+pub struct UbiRequest;
+impl UbiRequest {
+    pub fn method(&self) -> &str { todo!() }
+    pub fn path(&self) -> &str { todo!() }
+    pub fn header(&self, name: &str) -> Option<&String> { todo!() }
+    pub fn cookie(&self, name: &str) -> Option<&String> { todo!() }
+    pub fn ip(&self) -> Option<&str> { todo!() }
+    pub fn params(&self, name: &str) -> Option<&String> { todo!() }
+    pub fn query(&self, name: &str) -> Option<&String> { todo!() }
+    pub fn query_all(&self, name: &str) -> Vec<&String> { todo!() }
+    pub fn query_as<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, UbiError> { todo!() }
+    pub fn query_all_as<T: std::str::FromStr>(&self, name: &str) -> Result<Vec<T>, UbiError> { todo!() }
+    pub fn data<T: Validate>(&self) -> Result<T, UbiError> { todo!() }
+}
+pub struct UbiResponse;
+impl UbiResponse {
+    pub fn new() -> Self { todo!() }
+    pub fn status(self, status: u16) -> Self { todo!() }
+    pub fn header(self, name: impl ToString, value: impl ToString) -> Self { todo!() }
+    pub fn cookie(self, name: impl ToString, value: impl ToString) -> Self { todo!() }
+    pub fn body(self, body: impl ToString) -> Self { todo!() }
+}
+impl From<String> for UbiResponse { fn from(v: String) -> Self { todo!() } }
+impl From<&str> for UbiResponse { fn from(v: &str) -> Self { todo!() } }
+pub enum SqlParam { Null }
+impl From<&str> for SqlParam { fn from(v: &str) -> Self { todo!() } }
+impl From<String> for SqlParam { fn from(v: String) -> Self { todo!() } }
+impl From<&String> for SqlParam { fn from(v: &String) -> Self { todo!() } }
+impl From<bool> for SqlParam { fn from(v: bool) -> Self { todo!() } }
+impl From<i32> for SqlParam { fn from(v: i32) -> Self { todo!() } }
+impl From<i64> for SqlParam { fn from(v: i64) -> Self { todo!() } }
+impl From<f64> for SqlParam { fn from(v: f64) -> Self { todo!() } }
+impl<T: Into<SqlParam>> From<Option<T>> for SqlParam { fn from(v: Option<T>) -> Self { todo!() } }
+pub trait Validate {
+    fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>);
+    fn optional() -> bool { false }
+}
+impl Validate for String { fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {} }
+impl Validate for f64 { fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {} }
+impl Validate for bool { fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {} }
+impl Validate for Value { fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {} }
+impl<T: Validate> Validate for Vec<T> { fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {} }
+impl<T: Validate> Validate for Option<T> { fn validate(value: &Value, path: &str, errors: &mut Vec<FieldError>) {} }
+pub fn validate_object<'a>(value: &'a Value, path: &str, errors: &mut Vec<FieldError>) -> Option<&'a Map<String, Value>> { todo!() }
+pub fn validate_field<T: Validate>(object: &Map<String, Value>, path: &str, name: &str, errors: &mut Vec<FieldError>) {}
+"#;
+
+    /// Type-checks a generated route module against `HANDLER_API` with rustc.
+    fn type_check(rust: &str) {
+        static CHECKS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let check = CHECKS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = env::temp_dir().join(format!("ubi-type-check-{}-{}", std::process::id(), check));
+        fs::create_dir_all(&dir).unwrap();
+
+        // serde isn't there, its derives are left out.
+        let derive_re = Regex::new(r",\s*serde::(Deserialize|Serialize)").unwrap();
+        let source = format!(
+            "{}\nmod route {{\nuse crate::serde_json;\n{}\n}}\n",
+            HANDLER_API,
+            derive_re.replace_all(rust, "")
+        );
+        fs::write(dir.join("check.rs"), &source).unwrap();
+        let rustc = StdCommand::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()))
+            .args(["--edition", "2021", "--crate-type", "lib", "--emit", "metadata", "--out-dir"])
+            .arg(&dir)
+            .arg(dir.join("check.rs"))
+            .output()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            rustc.status.success(),
+            "{}\n{}",
+            rust,
+            String::from_utf8_lossy(&rustc.stderr)
+        );
+    }
+
+    /// `(name, signature)` of the handlers of a generated route file.
+    fn handler_signatures(rust: &str) -> Vec<(String, String)> {
+        let signature_re = Regex::new(r"(?m)^\s*pub fn (\w+)(\(.*\) -> .*?)\s*\{").unwrap();
+        signature_re
+            .captures_iter(rust)
+            .filter(|captures| HANDLER_METHODS.contains(&&captures[1]))
+            .map(|captures| (captures[1].to_string(), captures[2].to_string()))
+            .collect()
+    }
+
+    fn lower_typescript(source: &str) -> String {
+        ts::lower("routes/server.ts", source, &HashMap::new())
+            .unwrap_or_else(|e| panic!("{}", e))
+            .rust
+    }
+
+    #[test]
+    fn python_and_typescript_routes_lower_to_the_same_handlers() {
+        for (typescript, _, py2many) in CORPUS {
+            let python = lower_py2many(py2many);
+            let python = format!("{}\n{}", python, python_validate_impls(&python));
+            let typescript = lower_typescript(typescript);
+
+            let signatures = handler_signatures(&typescript);
+            assert!(!signatures.is_empty());
+            for (_, signature) in &signatures {
+                assert_eq!(
+                    signature,
+                    "(db: &crate::PgConnection, req: crate::UbiRequest) -> Result<crate::UbiResponse, crate::UbiError>"
+                );
+            }
+            assert_eq!(handler_signatures(&python), signatures, "\n{}", python);
+            type_check(&typescript);
+            type_check(&python);
+        }
+    }
+
+    #[test]
+    fn python_request_values_are_owned() {
+        let rust = lower_py2many(CORPUS[0].2);
+        assert!(rust.contains(r#"let id: String = req.params(&"id").cloned().unwrap_or_default();"#), "{}", rust);
+        assert!(rust.contains(r#"let token: Option<String> = req.header(&"x-token").cloned();"#), "{}", rust);
+        assert!(rust.contains(r#"let page: f64 = req.query_as::<f64>(&"page")?.unwrap_or_default();"#), "{}", rust);
+        assert!(rust.contains("let method: String = req.method().to_string();"), "{}", rust);
+        assert!(rust.contains("sql_params![id.clone(), page.clone()])?;"), "{}", rust);
+    }
+
+    /// Runs the corpus through py2many itself, which `ubi setup` installs:
+    /// `cargo test -- --ignored`. A failure here means py2many writes
+    /// something else than the third column of `CORPUS`.
+    #[test]
+    #[ignore]
+    fn corpus_matches_what_py2many_writes() {
+        let dir = env::temp_dir().join(format!("ubi-corpus-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for (i, (typescript, python, _)) in CORPUS.iter().enumerate() {
+            let input = dir.join(format!("server_{}.py", i));
+            let output = dir.join(format!("api_{}.rs", i));
+            fs::write(&input, python).unwrap();
+            convert_py_to_rust(input.to_str().unwrap(), output.to_str().unwrap())
+                .unwrap_or_else(|e| panic!("{}", e));
+
+            let rust = fs::read_to_string(&output).unwrap();
+            assert_eq!(handler_signatures(&rust), handler_signatures(&lower_typescript(typescript)), "\n{}", rust);
+            type_check(&rust);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! Structural search and replace over the Rust py2many writes, in the spirit
+//! of comby. In a template `:[name]` matches any text whose brackets and
+//! strings are balanced, `:[[name]]` a type on one line like `Vec<Data>`, and
+//! a run of whitespace matches any whitespace, none included. A hole that
+//! isn't closed by a bracket of the template stays in its statement, it
+//! doesn't match past a `;` or the end of the line.
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Hole {
+    /// Followed by a closing bracket of the template, may span lines.
+    Enclosed,
+    Inline,
+    Type,
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Space,
+    Hole(&'a str, Hole),
+}
+
+fn parse(template: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        let hole = if let Some(after) = rest.strip_prefix(":[[") {
+            after.find("]]").map(|end| (Part::Hole(&after[..end], Hole::Type), &after[end + 2..]))
+        } else if let Some(after) = rest.strip_prefix(":[") {
+            after.find(']').map(|end| (Part::Hole(&after[..end], Hole::Inline), &after[end + 1..]))
+        } else {
+            None
+        };
+        if let Some((part, after)) = hole {
+            parts.push(part);
+            rest = after;
+            continue;
+        }
+
+        let spaces = rest.len() - rest.trim_start().len();
+        if spaces > 0 {
+            parts.push(Part::Space);
+            rest = &rest[spaces..];
+            continue;
+        }
+
+        let end = rest
+            .char_indices()
+            .skip(1)
+            .find(|(i, c)| c.is_whitespace() || rest[*i..].starts_with(":["))
+            .map_or(rest.len(), |(i, _)| i);
+        parts.push(Part::Text(&rest[..end]));
+        rest = &rest[end..];
+    }
+
+    for i in 0..parts.len() {
+        let closed = parts[i + 1..]
+            .iter()
+            .find(|part| !matches!(part, Part::Space))
+            .is_some_and(|part| matches!(part, Part::Text(text) if text.starts_with([')', ']', '}'])));
+        if let Part::Hole(_, kind @ Hole::Inline) = &mut parts[i] {
+            if closed {
+                *kind = Hole::Enclosed;
+            }
+        }
+    }
+
+    parts
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Index right after the bracket or string starting at `open`.
+fn skip_group(source: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = source[open..].char_indices();
+    while let Some((mut i, c)) = chars.next() {
+        match c {
+            '"' => loop {
+                match chars.next()? {
+                    (_, '\\') => {
+                        chars.next()?;
+                    }
+                    (quote, '"') => {
+                        i = quote;
+                        break;
+                    }
+                    _ => {}
+                }
+            },
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return Some(open + i + 1);
+        }
+    }
+    None
+}
+
+fn match_parts<'t, 's>(
+    parts: &[Part<'t>],
+    source: &'s str,
+    pos: usize,
+    holes: &mut Vec<(&'t str, &'s str)>,
+) -> Option<usize> {
+    let Some((part, rest)) = parts.split_first() else {
+        return Some(pos);
+    };
+
+    match part {
+        Part::Text(text) => {
+            let end = pos + text.len();
+            let matches = source[pos..].starts_with(text)
+                // `ubi.req.method` isn't the start of `ubi.req.methods`.
+                && !(text.starts_with(is_word) && source[..pos].ends_with(is_word))
+                && !(text.ends_with(is_word) && source[end..].starts_with(is_word));
+            if !matches {
+                return None;
+            }
+            match_parts(rest, source, end, holes)
+        }
+        Part::Space => {
+            let end = source.len() - source[pos..].trim_start().len();
+            match_parts(rest, source, end, holes)
+        }
+        Part::Hole(name, kind) => {
+            let mut end = pos;
+            loop {
+                if *kind != Hole::Type || end > pos {
+                    holes.push((name, &source[pos..end]));
+                    if let Some(matched) = match_parts(rest, source, end, holes) {
+                        return Some(matched);
+                    }
+                    holes.pop();
+                }
+
+                let c = source[end..].chars().next()?;
+                end = match (kind, c) {
+                    (Hole::Type, c) if is_word(c) || "<>:&', ".contains(c) => end + c.len_utf8(),
+                    (Hole::Type, _) => return None,
+                    (_, '"' | '(' | '[' | '{') => skip_group(source, end)?,
+                    (_, ')' | ']' | '}') => return None,
+                    (Hole::Inline, ';' | '\n') => return None,
+                    (_, c) => end + c.len_utf8(),
+                };
+            }
+        }
+    }
+}
+
+/// Replaces every match of `template` in `source` with what `replace` makes
+/// of its holes.
+pub fn replace_all_with(
+    source: &str,
+    template: &str,
+    replace: impl Fn(&HashMap<&str, &str>) -> String,
+) -> String {
+    let parts = parse(template);
+    let mut hasil = String::new();
+    let mut pos = 0;
+
+    while let Some(c) = source[pos..].chars().next() {
+        let mut holes = Vec::new();
+        match match_parts(&parts, source, pos, &mut holes) {
+            Some(end) if end > pos => {
+                hasil.push_str(&replace(&holes.into_iter().collect()));
+                pos = end;
+            }
+            _ => {
+                hasil.push(c);
+                pos += c.len_utf8();
+            }
+        }
+    }
+
+    hasil
+}
+
+/// Replaces every match of `template` in `source` with `replacement`, where
+/// the holes of the template are filled in.
+pub fn replace_all(source: &str, template: &str, replacement: &str) -> String {
+    replace_all_with(source, template, |holes| {
+        let mut hasil = replacement.to_string();
+        for (name, value) in holes {
+            hasil = hasil
+                .replace(&format!(":[[{}]]", name), value)
+                .replace(&format!(":[{}]", name), value);
+        }
+        hasil
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holes_match_balanced_text() {
+        assert_eq!(
+            replace_all(r#"x = ubi.json(f(a, ")"), [b]);"#, "ubi.json(:[1])", "json!(:[1])"),
+            r#"x = json!(f(a, ")"), [b]);"#
+        );
+        assert_eq!(
+            replace_all("if ubi.transaction() {\n    a();\n    b();\n}", "if ubi.transaction() {:[1]}", "tx(|| {:[1]})"),
+            "tx(|| {\n    a();\n    b();\n})"
+        );
+    }
+
+    #[test]
+    fn inline_holes_stay_in_their_statement() {
+        let source = "let a: i32 = 1;\nlet b: Vec<Data> = ubi.query(\"q\");";
+        assert_eq!(
+            replace_all(source, ": :[[t]] = ubi.query(:[1])", " = db.query(:[1])"),
+            "let a: i32 = 1;\nlet b = db.query(\"q\");"
+        );
+        assert_eq!(
+            replace_all("return a; return b(c);", "return :[1];", "return Ok(:[1]);"),
+            "return Ok(a); return Ok(b(c));"
+        );
+    }
+
+    #[test]
+    fn text_matches_whole_words() {
+        assert_eq!(
+            replace_all("ubi.req.methods ubi.req.method", "ubi.req.method", "req.method()"),
+            "ubi.req.methods req.method()"
+        );
+        assert_eq!(replace_all("&strange &str", "&str", "String"), "&strange String");
+    }
+}
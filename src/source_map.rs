@@ -0,0 +1,278 @@
+//! Maps the lines of the generated route files back to the server.ts or
+//! server.py they were transpiled from, so the errors cargo reports about
+//! .project_build/src/server (and src/shared, for lib/) can point at the code
+//! the user wrote.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+/// Written next to every generated route file as `<file>.rs.map`.
+pub struct SourceMap {
+    /// The route file, relative to the project root.
+    pub source: String,
+    /// For every line of the generated file, the line of `source` it came
+    /// from, 0 when it doesn't come from a particular line.
+    pub lines: Vec<u32>,
+}
+
+impl SourceMap {
+    fn path(rust_file: &Path) -> PathBuf {
+        let mut path = rust_file.as_os_str().to_owned();
+        path.push(".map");
+        PathBuf::from(path)
+    }
+
+    pub fn read(rust_file: &Path) -> Option<SourceMap> {
+        let map: Value = serde_json::from_str(&fs::read_to_string(Self::path(rust_file)).ok()?).ok()?;
+        Some(SourceMap {
+            source: map["source"].as_str()?.to_string(),
+            lines: map["lines"]
+                .as_array()?
+                .iter()
+                .map(|line| line.as_u64().unwrap_or(0) as u32)
+                .collect(),
+        })
+    }
+
+    pub fn write(&self, rust_file: &Path) -> io::Result<()> {
+        let map = json!({ "source": self.source, "lines": self.lines });
+        fs::write(Self::path(rust_file), map.to_string())
+    }
+
+    /// The source line of the 1-based `line` of the generated file.
+    pub fn original_line(&self, line: u32) -> Option<u32> {
+        self.lines
+            .get(line.checked_sub(1)? as usize)
+            .copied()
+            .filter(|&line| line > 0)
+    }
+}
+
+/// Prints the errors of `cargo build --message-format=json`, the ones in a
+/// generated route file pointing at its route file instead. Returns how many
+/// errors were printed.
+pub fn report_cargo_errors(stdout: &[u8], project_root: &Path, build_dir: &Path) -> usize {
+    // Several Rust errors can come from the same line of a route file.
+    let mut printed = HashSet::new();
+
+    for line in String::from_utf8_lossy(stdout).lines() {
+        let Ok(message) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        if message["reason"] != "compiler-message" || message["message"]["level"] != "error" {
+            continue;
+        }
+        let message = &message["message"];
+        let spans = message["spans"].as_array().cloned().unwrap_or_default();
+        // The "aborting due to N previous errors" summary has no span.
+        if spans.is_empty() {
+            continue;
+        }
+
+        let primary = spans.iter().find(|span| span["is_primary"] == true).unwrap_or(&spans[0]);
+        let file_name = primary["file_name"].as_str().unwrap_or_default();
+        let map = if file_name.starts_with("src/server/") || file_name.starts_with("src/shared/") {
+            SourceMap::read(&build_dir.join(file_name))
+        } else {
+            None
+        };
+
+        let (key, rendered) = match map {
+            Some(map) => {
+                let line = map.original_line(primary["line_start"].as_u64().unwrap_or(0) as u32);
+                (
+                    format!("{}:{:?}:{}", map.source, line, message["message"]),
+                    render_mapped(message, primary, &map, project_root),
+                )
+            }
+            None => {
+                let rendered = message["rendered"].as_str().unwrap_or_default().to_string();
+                (rendered.clone(), rendered)
+            }
+        };
+        if printed.insert(key) {
+            eprintln!("{}", rendered);
+        }
+    }
+
+    printed.len()
+}
+
+fn render_mapped(message: &Value, span: &Value, map: &SourceMap, project_root: &Path) -> String {
+    let code = message["code"]["code"]
+        .as_str()
+        .map(|code| format!("[{}]", code))
+        .unwrap_or_default();
+    let generated_line = span["line_start"].as_u64().unwrap_or(0) as u32;
+    let generated = format!(
+        ".project_build/{}:{}:{}",
+        span["file_name"].as_str().unwrap_or_default(),
+        generated_line,
+        span["column_start"].as_u64().unwrap_or(0)
+    );
+
+    let mut hasil = format!("error{}: {}\n", code, message["message"].as_str().unwrap_or_default());
+    match map.original_line(generated_line) {
+        Some(line) => {
+            let text = fs::read_to_string(project_root.join(&map.source))
+                .ok()
+                .and_then(|source| source.lines().nth(line as usize - 1).map(str::to_string))
+                .unwrap_or_default();
+            hasil.push_str(&format!("  --> {}:{}\n   |\n{:>2} | {}\n   |\n", map.source, line, line, text.trim_end()));
+        }
+        None => hasil.push_str(&format!("  --> {}\n   |\n", map.source)),
+    }
+    if let Some(label) = span["label"].as_str() {
+        hasil.push_str(&format!("   = {}\n", label));
+    }
+    if map.source.ends_with(".py") && map.original_line(generated_line).is_some() {
+        hasil.push_str("   = the line is approximate, py2many doesn't say which line its output comes from\n");
+    }
+    hasil.push_str(&format!("   = in the generated {}\n", generated));
+
+    hasil
+}
+
+/// For every line of `rust`, the line of the Python route it was made of, 0
+/// when there is none. py2many doesn't say where its output comes from, so a
+/// `fn` or `struct` is matched to its `def` or `class`, and the other lines
+/// to the line from the last match on, up to the next `def` or `class`, that
+/// has the most names and strings in common with them.
+pub fn python_lines(python: &str, rust: &str) -> Vec<u32> {
+    // Names any line of a handler may have, they don't tell the lines apart.
+    const COMMON: &[&str] = &[
+        "def", "class", "let", "mut", "fn", "pub", "crate", "ubi", "req", "db", "query", "json",
+        "str", "String", "int", "float", "bool", "None", "Some", "Ok", "True", "False", "true",
+        "false", "to_string", "clone", "from", "import",
+    ];
+    let token_re = Regex::new(r#""((?:[^"\\]|\\.)+)"|'((?:[^'\\]|\\.)+)'|([A-Za-z_]\w+)"#).unwrap();
+    let tokens = |line: &str| -> HashSet<String> {
+        token_re
+            .captures_iter(line)
+            .filter_map(|captures| captures.get(1).or(captures.get(2)).or(captures.get(3)))
+            .map(|token| token.as_str().to_string())
+            .filter(|token| !COMMON.contains(&token.as_str()))
+            .collect()
+    };
+
+    let item_re = Regex::new(r"^\s*(?:def|class)\s+(\w+)").unwrap();
+    let python: Vec<&str> = python.lines().collect();
+    let words: Vec<_> = python.iter().map(|line| tokens(line)).collect();
+    let items: Vec<(usize, &str)> = python
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| Some((i, item_re.captures(line)?.get(1)?.as_str())))
+        .collect();
+
+    let rust_item_re = Regex::new(r"\b(?:fn|struct)\s+(\w+)").unwrap();
+    let (mut from, mut to) = (0, python.len());
+    rust.lines()
+        .map(|line| {
+            let item = rust_item_re.captures(line).and_then(|captures| {
+                let name = captures.get(1)?.as_str();
+                items.iter().position(|(_, item)| *item == name)
+            });
+            if let Some(index) = item {
+                from = items[index].0;
+                to = items.get(index + 1).map_or(python.len(), |(i, _)| *i);
+                return from as u32 + 1;
+            }
+
+            let line = tokens(line);
+            if line.is_empty() {
+                return 0;
+            }
+            let best = (from..to)
+                .map(|i| (words[i].intersection(&line).count(), std::cmp::Reverse(i)))
+                .filter(|(common, _)| *common > 0)
+                .max();
+            match best {
+                Some((_, std::cmp::Reverse(i))) => {
+                    from = i;
+                    i as u32 + 1
+                }
+                None => 0,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PYTHON: &str = r#"from typing import TypedDict
+
+class Data(TypedDict):
+    tes: str
+
+def get() -> str:
+    id: str = ubi.req.params("id")
+    hasil: Data = ubi.query("select * from tes where id = $1", [id])
+    return ubi.json(hasil)
+"#;
+
+    // py2many's output for PYTHON, rewritten by convert_py_to_rust.
+    const RUST: &str = r#"use std::io::BufRead;
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Data {
+    pub tes: String,
+}
+
+pub fn get(db: &crate::PgConnection, req: crate::UbiRequest) -> Result<crate::UbiResponse, crate::UbiError> {
+    let id: String = req.params(&"id").cloned().unwrap_or_default();
+    let hasil: Data = db.query("select * from tes where id = $1", sql_params![id])?;
+    return Ok(crate::UbiResponse::from(serde_json::json!(hasil).to_string()));
+}
+"#;
+
+    #[test]
+    fn python_lines_are_matched_by_their_names_and_strings() {
+        assert_eq!(python_lines(PYTHON, RUST), [0, 0, 3, 4, 0, 0, 6, 7, 8, 9, 0]);
+    }
+
+    #[test]
+    fn cargo_error_points_at_the_python_line() {
+        let root = std::env::temp_dir().join(format!("ubi-source-map-{}", std::process::id()));
+        fs::create_dir_all(root.join("routes")).unwrap();
+        fs::write(root.join("routes/server.py"), PYTHON).unwrap();
+        let map = SourceMap {
+            source: "routes/server.py".to_string(),
+            lines: python_lines(PYTHON, RUST),
+        };
+
+        // As cargo reports a query whose rows don't fit `Data`.
+        let message = json!({
+            "message": "mismatched types",
+            "code": { "code": "E0308" },
+            "spans": [{
+                "file_name": "src/server/api.rs",
+                "line_start": 9,
+                "column_start": 23,
+                "is_primary": true,
+                "label": "expected `Data`, found `Vec<Data>`"
+            }]
+        });
+        let rendered = render_mapped(&message, &message["spans"][0], &map, &root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            rendered,
+            r#"error[E0308]: mismatched types
+  --> routes/server.py:8
+   |
+ 8 |     hasil: Data = ubi.query("select * from tes where id = $1", [id])
+   |
+   = expected `Data`, found `Vec<Data>`
+   = the line is approximate, py2many doesn't say which line its output comes from
+   = in the generated .project_build/src/server/api.rs:9:23
+"#
+        );
+    }
+}
@@ -0,0 +1,2741 @@
+//! TypeScript front end of the transpiler. A server.ts is parsed with oxc and
+//! the subset Ubi supports is lowered straight to the Rust the runtime in
+//! project/src/main.rs expects. Whatever falls outside of that subset is
+//! reported with the line and column it was found at, instead of ending up as
+//! Rust that doesn't compile.
+
+use std::collections::{HashMap, HashSet};
+
+use oxc_allocator::Allocator;
+use oxc_ast::ast::*;
+use oxc_ast_visit::{walk, Visit};
+use oxc_parser::Parser;
+use oxc_span::{GetSpan, SourceType, Span};
+
+use crate::HANDLER_METHODS;
+
+const RUST_KEYWORDS: [&str; 38] = [
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use",
+    "where", "while", "yield",
+];
+
+fn rust_ident(name: &str) -> String {
+    if RUST_KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// A Rust string literal holding `value`.
+fn rust_str(value: &str) -> String {
+    format!("{:?}", value)
+}
+
+struct Diagnostic {
+    span: Span,
+    message: String,
+}
+
+/// A field of a struct lowered from a `type`. Object literals need them to
+/// fill in the optional fields they leave out.
+#[derive(Clone)]
+struct Field {
+    name: String,
+    rust_type: String,
+    optional: bool,
+    /// Line of the field in server.ts.
+    line: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    TopLevel,
+    Function,
+    Handler,
+    /// The callback of `ubi.transaction`, inside a handler.
+    Transaction,
+}
+
+#[derive(Clone)]
+struct Signature {
+    params: Vec<(String, String)>,
+    returns: Option<String>,
+}
+
+/// What `break` and `continue` jump out of.
+enum Jump {
+    /// `next` is what has to run before `continue`, the update of a `for`
+    /// or the condition of a `do ... while`, both lowered at the end of the
+    /// body.
+    Loop { next: Option<String> },
+    Switch,
+}
+
+/// The Rust of a route file, and for every line of it the server.ts line it
+/// came from (0 when it has none), the source map cargo errors are read
+/// through.
+pub struct Lowered {
+    pub rust: String,
+    pub lines: Vec<u32>,
+    /// What the file declares, for the files importing it when it is a
+    /// module of lib/.
+    pub exports: Exports,
+}
+
+/// The types and functions of a module of lib/, route files importing them
+/// are lowered knowing their fields and parameters.
+#[derive(Default)]
+pub struct Exports {
+    structs: HashMap<String, Vec<Field>>,
+    enums: HashMap<String, Vec<String>>,
+    functions: HashMap<String, Signature>,
+    /// Python modules are lowered by py2many, whatever they declare is
+    /// taken on trust.
+    opaque: bool,
+}
+
+impl Exports {
+    pub fn opaque() -> Exports {
+        Exports {
+            opaque: true,
+            ..Exports::default()
+        }
+    }
+}
+
+/// A top level declaration, exported or not.
+enum Item<'b, 'a> {
+    Function(&'b Function<'a>),
+    Alias(&'b TSTypeAliasDeclaration<'a>),
+    Interface(&'b TSInterfaceDeclaration<'a>),
+    Import(&'b ImportDeclaration<'a>),
+}
+
+struct Lowerer<'s> {
+    source: &'s str,
+    line_starts: Vec<u32>,
+    /// The server.ts line the next emitted lines come from.
+    origin: u32,
+    /// Structs and type aliases, they go before the functions.
+    types: String,
+    type_lines: Vec<u32>,
+    out: String,
+    out_lines: Vec<u32>,
+    indent: usize,
+    scope: Scope,
+    structs: HashMap<String, Vec<Field>>,
+    /// Enums lowered from unions of string literals, with their values.
+    enums: HashMap<String, Vec<String>>,
+    /// Parameter and return types of the functions of the file.
+    functions: HashMap<String, Signature>,
+    /// Rust types of the variables of the current function, as far as they
+    /// are known. Number literals are lowered after them.
+    locals: HashMap<String, String>,
+    returns: Option<String>,
+    /// Variables of the current function that need `let mut`.
+    mutated: HashSet<String>,
+    jumps: Vec<Jump>,
+    /// Lowering a module of lib/ rather than a route file.
+    library: bool,
+    shared: &'s HashMap<String, Exports>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Lowers the server.ts `source` to Rust. `file_name` is only used to point
+/// at the problems when there are any, `shared` are the modules of lib/ it
+/// can import.
+pub fn lower(file_name: &str, source: &str, shared: &HashMap<String, Exports>) -> Result<Lowered, String> {
+    lower_file(file_name, source, false, shared)
+}
+
+/// Lowers a module of lib/, everything it declares is `pub` and there are
+/// no handlers.
+pub fn lower_library(file_name: &str, source: &str, shared: &HashMap<String, Exports>) -> Result<Lowered, String> {
+    lower_file(file_name, source, true, shared)
+}
+
+/// The modules of lib/ `source` imports, `text/format` for
+/// `import { slug } from "$lib/text/format"`.
+pub fn imports(source: &str) -> Vec<String> {
+    let allocator = Allocator::default();
+    let parsed = Parser::new(&allocator, source, SourceType::ts()).parse();
+    parsed
+        .program
+        .body
+        .iter()
+        .filter_map(|statement| match statement {
+            Statement::ImportDeclaration(import) => import.source.value.strip_prefix("$lib/").map(str::to_string),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The name of the module of `crate::shared` a file of lib/ becomes,
+/// `text_format` for lib/text/format.ts.
+pub fn shared_module(path: &str) -> String {
+    path.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+fn lower_file(
+    file_name: &str,
+    source: &str,
+    library: bool,
+    shared: &HashMap<String, Exports>,
+) -> Result<Lowered, String> {
+    let allocator = Allocator::default();
+    let parsed = Parser::new(&allocator, source, SourceType::ts()).parse();
+
+    let line_starts = std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i as u32 + 1))
+        .collect();
+    let mut lowerer = Lowerer {
+        source,
+        line_starts,
+        origin: 0,
+        types: String::new(),
+        type_lines: Vec::new(),
+        out: String::new(),
+        out_lines: Vec::new(),
+        indent: 0,
+        scope: Scope::TopLevel,
+        structs: HashMap::new(),
+        enums: HashMap::new(),
+        functions: HashMap::new(),
+        locals: HashMap::new(),
+        returns: None,
+        mutated: HashSet::new(),
+        jumps: Vec::new(),
+        library,
+        shared,
+        diagnostics: Vec::new(),
+    };
+
+    for error in &parsed.errors {
+        let start = error
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.first())
+            .map_or(0, |label| label.offset() as u32);
+        lowerer.error(Span::new(start, start), error.message.to_string());
+    }
+    if lowerer.diagnostics.is_empty() {
+        lowerer.program(&parsed.program);
+    }
+
+    if lowerer.diagnostics.is_empty() {
+        let mut lines = lowerer.type_lines;
+        lines.extend(lowerer.out_lines);
+        Ok(Lowered {
+            rust: format!("{}{}", lowerer.types, lowerer.out),
+            lines,
+            exports: Exports {
+                structs: lowerer.structs,
+                enums: lowerer.enums,
+                functions: lowerer.functions,
+                opaque: false,
+            },
+        })
+    } else {
+        Err(render_diagnostics(file_name, source, lowerer.diagnostics))
+    }
+}
+
+/// Renders a single error at `offset` of `source` the way the errors of a
+/// server.ts are, for the .ubi compiler.
+pub fn render_error(file_name: &str, source: &str, offset: usize, message: String) -> String {
+    let span = Span::new(offset as u32, offset as u32);
+    render_diagnostics(file_name, source, vec![Diagnostic { span, message }])
+}
+
+fn render_diagnostics(file_name: &str, source: &str, mut diagnostics: Vec<Diagnostic>) -> String {
+    diagnostics.sort_by_key(|d| d.span.start);
+
+    let mut hasil = format!(
+        "{} error{} in {}\n",
+        diagnostics.len(),
+        if diagnostics.len() == 1 { "" } else { "s" },
+        file_name
+    );
+
+    for diagnostic in diagnostics {
+        let start = (diagnostic.span.start as usize).min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let line = source[..start].matches('\n').count() + 1;
+        let text = &source[line_start..line_end];
+        let before = &source[line_start..start];
+        // Tabs are kept so the caret lines up with the source line.
+        let padding: String = before.chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+
+        hasil.push_str(&format!(
+            "\nerror: {}\n  --> {}:{}:{}\n   |\n{:>2} | {}\n   | {}^\n",
+            diagnostic.message,
+            file_name,
+            line,
+            before.chars().count() + 1,
+            line,
+            text.trim_end(),
+            padding
+        ));
+    }
+
+    hasil
+}
+
+/// `["ubi", "req", "query"]` for `ubi.req.query`.
+fn member_path<'a>(expr: &'a Expression) -> Option<Vec<&'a str>> {
+    match expr {
+        Expression::Identifier(id) => Some(vec![id.name.as_str()]),
+        Expression::StaticMemberExpression(member) => {
+            let mut path = member_path(&member.object)?;
+            path.push(member.property.name.as_str());
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+fn property_name(key: &PropertyKey) -> Option<String> {
+    match key {
+        PropertyKey::StaticIdentifier(id) => Some(id.name.to_string()),
+        PropertyKey::StringLiteral(s) => Some(s.value.to_string()),
+        _ => None,
+    }
+}
+
+/// The Rust type a number, string or boolean query parameter is read as.
+fn query_type(ty: &TSType) -> Option<&'static str> {
+    match ty {
+        TSType::TSNumberKeyword(_) => Some("f64"),
+        TSType::TSStringKeyword(_) => Some("String"),
+        TSType::TSBooleanKeyword(_) => Some("bool"),
+        _ => None,
+    }
+}
+
+/// The Rust type of what `ubi.req` gives, `Option<String>` for
+/// `ubi.req.query("name")`.
+fn request_type(expr: &Expression) -> Option<String> {
+    match expr {
+        Expression::CallExpression(call) => {
+            let typed = call.type_arguments.as_ref().and_then(|arguments| arguments.params.first());
+            let read_as = typed.map_or(Some("String"), query_type)?;
+            match member_path(&call.callee)?.as_slice() {
+                ["ubi", "req", "params" | "header" | "cookie"] => Some("Option<String>".to_string()),
+                ["ubi", "req", "query"] => Some(format!("Option<{}>", read_as)),
+                ["ubi", "req", "queryAll"] => Some(format!("Vec<{}>", read_as)),
+                _ => None,
+            }
+        }
+        _ => match member_path(expr)?.as_slice() {
+            ["ubi", "req", "method" | "path"] => Some("String".to_string()),
+            ["ubi", "req", "ip"] => Some("Option<String>".to_string()),
+            _ => None,
+        },
+    }
+}
+
+fn is_string(expr: &Expression) -> bool {
+    matches!(expr, Expression::StringLiteral(_) | Expression::TemplateLiteral(_))
+}
+
+/// The operands of a chain of `+`.
+fn addition_operands<'b, 'a>(expr: &'b Expression<'a>, operands: &mut Vec<&'b Expression<'a>>) {
+    match expr {
+        Expression::BinaryExpression(binary) if binary.operator == BinaryOperator::Addition => {
+            addition_operands(&binary.left, operands);
+            addition_operands(&binary.right, operands);
+        }
+        _ => operands.push(expr),
+    }
+}
+
+/// Methods of arrays and maps that change them, calling them needs a `mut`
+/// binding in Rust even when TypeScript declared it `const`.
+const MUTATING_METHODS: [&str; 14] = [
+    "push", "pop", "shift", "unshift", "splice", "sort", "reverse", "insert", "remove", "clear",
+    "extend", "retain", "truncate", "append",
+];
+
+/// Collects the variables a function assigns, increments or mutates, the only
+/// ones declared `let mut`.
+#[derive(Default)]
+struct Mutations {
+    names: HashSet<String>,
+}
+
+impl Mutations {
+    fn of(body: &FunctionBody) -> HashSet<String> {
+        let mut mutations = Mutations::default();
+        mutations.visit_function_body(body);
+        mutations.names
+    }
+
+    /// `user` for `user.address.city`.
+    fn root(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Identifier(id) => {
+                self.names.insert(id.name.to_string());
+            }
+            Expression::StaticMemberExpression(member) => self.root(&member.object),
+            Expression::ComputedMemberExpression(member) => self.root(&member.object),
+            Expression::ParenthesizedExpression(inner) => self.root(&inner.expression),
+            _ => {}
+        }
+    }
+
+    fn target(&mut self, target: &SimpleAssignmentTarget) {
+        match target {
+            SimpleAssignmentTarget::AssignmentTargetIdentifier(id) => {
+                self.names.insert(id.name.to_string());
+            }
+            SimpleAssignmentTarget::StaticMemberExpression(member) => self.root(&member.object),
+            SimpleAssignmentTarget::ComputedMemberExpression(member) => self.root(&member.object),
+            _ => {}
+        }
+    }
+}
+
+impl<'a> Visit<'a> for Mutations {
+    fn visit_assignment_expression(&mut self, it: &AssignmentExpression<'a>) {
+        if let Some(target) = it.left.as_simple_assignment_target() {
+            self.target(target);
+        }
+        walk::walk_assignment_expression(self, it);
+    }
+
+    fn visit_update_expression(&mut self, it: &UpdateExpression<'a>) {
+        self.target(&it.argument);
+        walk::walk_update_expression(self, it);
+    }
+
+    fn visit_call_expression(&mut self, it: &CallExpression<'a>) {
+        if let Expression::StaticMemberExpression(member) = &it.callee {
+            if MUTATING_METHODS.contains(&member.property.name.as_str()) {
+                self.root(&member.object);
+            }
+        }
+        walk::walk_call_expression(self, it);
+    }
+}
+
+/// Whether nothing runs after `statements`, so a loop doesn't need its
+/// update or condition emitted after them.
+fn diverges(statements: &[Statement]) -> bool {
+    matches!(
+        statements.last(),
+        Some(
+            Statement::ReturnStatement(_)
+                | Statement::BreakStatement(_)
+                | Statement::ContinueStatement(_)
+                | Statement::ThrowStatement(_)
+        )
+    )
+}
+
+/// The statements of a loop body or a `case`, with or without braces.
+fn statements_of<'b, 'a>(statement: &'b Statement<'a>) -> &'b [Statement<'a>] {
+    match statement {
+        Statement::BlockStatement(block) => &block.body,
+        other => std::slice::from_ref(other),
+    }
+}
+
+/// `number` is an `f64`, the aliases `i32` and `i64` are the integers.
+fn is_integer(rust_type: &str) -> bool {
+    matches!(rust_type, "i32" | "i64" | "u16" | "usize")
+}
+
+/// `Option<T>` to `T`.
+fn option_inner(rust_type: &str) -> Option<&str> {
+    rust_type.strip_prefix("Option<")?.strip_suffix('>')
+}
+
+/// `Admin` for `"admin"`, `InProgress` for `"in-progress"`.
+fn variant_name(value: &str) -> String {
+    let mut name: String = value
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars.next().map(|c| c.to_ascii_uppercase()).into_iter().chain(chars).collect::<String>()
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, 'V');
+    }
+    name
+}
+
+/// The name of the struct or enum an inline type of `field` of `parent`
+/// becomes, `UserAddress` for `address` of `User`.
+fn nested_name(parent: &str, field: &str) -> String {
+    let mut chars = field.chars();
+    format!(
+        "{}{}{}",
+        parent,
+        chars.next().map(|c| c.to_ascii_uppercase()).unwrap_or_default(),
+        chars.as_str()
+    )
+}
+
+/// `rust_type` with the structs and enums of the lib/ `module` in it
+/// replaced by their path, `Vec<crate::shared::users::Profile>`.
+fn qualify(rust_type: &str, module: &str, exports: &Exports) -> String {
+    let mut hasil = String::new();
+    let mut rest = rust_type;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+            .unwrap_or(rest.len())
+            .max(1);
+        let (token, tail) = rest.split_at(end);
+        if exports.structs.contains_key(token) || exports.enums.contains_key(token) {
+            hasil.push_str(&format!("crate::shared::{}::{}", module, token));
+        } else {
+            hasil.push_str(token);
+        }
+        rest = tail;
+    }
+    hasil
+}
+
+/// Escapes literal text for the format string of `format!`.
+fn format_text(text: &str) -> String {
+    let literal = rust_str(text);
+    literal[1..literal.len() - 1].replace('{', "{{").replace('}', "}}")
+}
+
+impl<'s> Lowerer<'s> {
+    fn error(&mut self, span: Span, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            span,
+            message: message.into(),
+        });
+    }
+
+    fn text(&self, span: Span) -> &'s str {
+        &self.source[span.start as usize..span.end as usize]
+    }
+
+    /// Makes the lines emitted from now on point at the line of `span`.
+    fn at(&mut self, span: Span) {
+        self.origin = self.line_starts.partition_point(|&start| start <= span.start) as u32;
+    }
+
+    fn line(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.out.push_str(&"    ".repeat(self.indent));
+            self.out.push_str(text);
+        }
+        self.out.push('\n');
+        self.out_lines.push(self.origin);
+    }
+
+    fn type_line(&mut self, text: &str, line: u32) {
+        self.types.push_str(text);
+        self.types.push('\n');
+        self.type_lines.push(line);
+    }
+
+    fn program(&mut self, program: &Program) {
+        let mut items = Vec::new();
+        for statement in &program.body {
+            match statement {
+                Statement::FunctionDeclaration(function) => items.push(Item::Function(function)),
+                Statement::TSTypeAliasDeclaration(alias) => items.push(Item::Alias(alias)),
+                Statement::TSInterfaceDeclaration(interface) => items.push(Item::Interface(interface)),
+                Statement::ImportDeclaration(import) => items.push(Item::Import(import)),
+                Statement::EmptyStatement(_) => {}
+                Statement::ExportNamedDeclaration(export) if self.library => match &export.declaration {
+                    Some(Declaration::FunctionDeclaration(function)) => items.push(Item::Function(function)),
+                    Some(Declaration::TSTypeAliasDeclaration(alias)) => items.push(Item::Alias(alias)),
+                    Some(Declaration::TSInterfaceDeclaration(interface)) => items.push(Item::Interface(interface)),
+                    _ => self.error(export.span, "only functions and types can be exported"),
+                },
+                Statement::ExportNamedDeclaration(export) => self.error(
+                    export.span,
+                    "route files can't export anything, move what other files need to a module of lib/",
+                ),
+                other => self.error(
+                    other.span(),
+                    "only functions and types can be declared at the top level of a route file",
+                ),
+            }
+        }
+
+        // Imports first, then types, object literals need their fields
+        // wherever they are declared in the file.
+        for item in &items {
+            match item {
+                Item::Import(import) => self.import(import),
+                Item::Alias(alias) => self.type_alias(alias),
+                Item::Interface(interface) => {
+                    if !interface.extends.is_empty() {
+                        self.error(interface.span, "interfaces can't extend other types");
+                    }
+                    self.declare_struct(interface.id.name.as_str(), interface.span, &interface.body.body);
+                }
+                Item::Function(_) => {}
+            }
+        }
+
+        // Then the signatures, calls need the types of the parameters to
+        // lower their number arguments.
+        for item in &items {
+            if let Item::Function(function) = item {
+                if let Some(id) = &function.id {
+                    if !self.is_handler(&id.name) {
+                        let signature = self.signature(function);
+                        self.functions.insert(id.name.to_string(), signature);
+                    }
+                }
+            }
+        }
+
+        for item in &items {
+            if let Item::Function(function) = item {
+                self.function(function);
+            }
+        }
+    }
+
+    fn is_handler(&self, name: &str) -> bool {
+        !self.library && HANDLER_METHODS.contains(&name)
+    }
+
+    /// `pub ` for the items of lib/, route files only expose their handlers.
+    fn visibility(&self) -> &'static str {
+        if self.library {
+            "pub "
+        } else {
+            ""
+        }
+    }
+
+    /// `import { slug, type User } from "$lib/text"` becomes a `use` of the
+    /// module of `crate::shared`, and what it imports is known to the rest
+    /// of the file.
+    fn import(&mut self, import: &ImportDeclaration) {
+        let Some(path) = import.source.value.strip_prefix("$lib/") else {
+            self.error(import.source.span, "only modules of lib/ can be imported, `import { ... } from \"$lib/utils\"`");
+            return;
+        };
+        let module = shared_module(path);
+        let Some(exports) = self.shared.get(&module) else {
+            self.error(import.source.span, format!("there is no lib/{}.ts or lib/{}.py", path, path));
+            return;
+        };
+
+        // The fields and parameters of what is imported can name other types
+        // of the module, they are known by their path from here.
+        let qualify = |rust_type: &str| qualify(rust_type, &module, exports);
+        for (name, fields) in &exports.structs {
+            let fields = fields
+                .iter()
+                .map(|field| Field {
+                    rust_type: qualify(&field.rust_type),
+                    ..field.clone()
+                })
+                .collect();
+            self.structs.insert(format!("crate::shared::{}::{}", module, name), fields);
+        }
+        for (name, values) in &exports.enums {
+            self.enums.insert(format!("crate::shared::{}::{}", module, name), values.clone());
+        }
+
+        let mut names = Vec::new();
+        for specifier in import.specifiers.iter().flatten() {
+            let ImportDeclarationSpecifier::ImportSpecifier(specifier) = specifier else {
+                self.error(specifier.span(), "only named imports are supported, `import { name } from ...`");
+                continue;
+            };
+            let imported = specifier.imported.name();
+            let local = specifier.local.name.as_str();
+
+            let mut found = exports.opaque;
+            let qualified = format!("crate::shared::{}::{}", module, imported);
+            if let Some(fields) = self.structs.get(&qualified) {
+                self.structs.insert(local.to_string(), fields.clone());
+                found = true;
+            }
+            if let Some(values) = exports.enums.get(imported.as_str()) {
+                self.enums.insert(local.to_string(), values.clone());
+                found = true;
+            }
+            if let Some(signature) = exports.functions.get(imported.as_str()) {
+                let signature = Signature {
+                    params: signature
+                        .params
+                        .iter()
+                        .map(|(name, rust_type)| (name.clone(), qualify(rust_type)))
+                        .collect(),
+                    returns: signature.returns.as_deref().map(qualify),
+                };
+                self.functions.insert(local.to_string(), signature);
+                found = true;
+            }
+            if !found {
+                self.error(specifier.span, format!("lib/{} has no `{}`", path, imported));
+                continue;
+            }
+
+            if imported.as_str() == local {
+                names.push(rust_ident(local));
+            } else {
+                names.push(format!("{} as {}", rust_ident(&imported), rust_ident(local)));
+            }
+        }
+
+        if !names.is_empty() {
+            self.at(import.span);
+            let line = self.origin;
+            self.type_line(
+                &format!("{}use crate::shared::{}::{{{}}};", self.visibility(), module, names.join(", ")),
+                line,
+            );
+            self.type_line("", 0);
+        }
+    }
+
+    fn type_alias(&mut self, alias: &TSTypeAliasDeclaration) {
+        let name = alias.id.name.as_str();
+        if alias.type_parameters.is_some() {
+            self.error(alias.span, "generic types are not supported");
+            return;
+        }
+
+        match &alias.type_annotation {
+            // `type i64 = number` only tells TypeScript about the integer
+            // types, Rust already has them.
+            TSType::TSNumberKeyword(_) if matches!(name, "i32" | "i64") => {}
+            TSType::TSTypeLiteral(literal) => self.declare_struct(name, alias.span, &literal.members),
+            other => {
+                let rust_type = self.rust_type(other, Some(name));
+                // Unions of strings are declared as an enum of that name.
+                if rust_type != name {
+                    self.at(alias.span);
+                    let line = format!("{}type {} = {};", self.visibility(), name, rust_type);
+                    self.type_line(&line, self.origin);
+                    self.type_line("", 0);
+                }
+            }
+        }
+    }
+
+    /// Emits the serde struct and the `Validate` impl of an object type.
+    fn declare_struct(&mut self, name: &str, span: Span, members: &[TSSignature]) {
+        let mut fields = Vec::new();
+
+        for member in members {
+            let TSSignature::TSPropertySignature(property) = member else {
+                self.error(member.span(), "only properties are supported in object types");
+                continue;
+            };
+            let Some(key) = property_name(&property.key) else {
+                self.error(property.key.span(), "computed property names are not supported");
+                continue;
+            };
+            let Some(annotation) = &property.type_annotation else {
+                self.error(property.span, format!("field `{}` of {} has no type", key, name));
+                continue;
+            };
+
+            let rust_type = self.rust_type(&annotation.type_annotation, Some(&nested_name(name, &key)));
+            self.at(property.span);
+            // `?:` and `| null` both make the field an `Option`, only once.
+            let (rust_type, optional) = match option_inner(&rust_type) {
+                Some(inner) => (inner.to_string(), true),
+                None => (rust_type, property.optional),
+            };
+            fields.push(Field {
+                name: key,
+                rust_type,
+                optional,
+                line: self.origin,
+            });
+        }
+
+        let field_type = |field: &Field| {
+            if field.optional {
+                format!("Option<{}>", field.rust_type)
+            } else {
+                field.rust_type.clone()
+            }
+        };
+
+        self.at(span);
+        let line = self.origin;
+        self.type_line("#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]", line);
+        let visibility = self.visibility();
+        self.type_line(&format!("{}struct {} {{", visibility, name), line);
+        for field in &fields {
+            self.type_line(
+                &format!("    {}{}: {},", visibility, rust_ident(&field.name), field_type(field)),
+                field.line,
+            );
+        }
+        self.type_line("}", line);
+        self.type_line("", 0);
+
+        self.type_line(&format!("impl crate::Validate for {} {{", name), line);
+        self.type_line(
+            "    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {",
+            line,
+        );
+        self.type_line("        if let Some(object) = crate::validate_object(value, path, errors) {", line);
+        for field in &fields {
+            self.type_line(
+                &format!(
+                    "            crate::validate_field::<{}>(object, path, {}, errors);",
+                    field_type(field),
+                    rust_str(&field.name)
+                ),
+                field.line,
+            );
+        }
+        self.type_line("        }", line);
+        self.type_line("    }", line);
+        self.type_line("}", line);
+        self.type_line("", 0);
+
+        self.structs.insert(name.to_string(), fields);
+    }
+
+    /// Emits a serde enum for a union of string literals, with the `Validate`
+    /// impl checking the value is one of them and `Display` giving it back.
+    fn declare_enum(&mut self, name: &str, span: Span, values: Vec<String>) {
+        let variants: Vec<String> = values.iter().map(|value| variant_name(value)).collect();
+        for (i, variant) in variants.iter().enumerate() {
+            if variants[..i].contains(variant) {
+                self.error(span, format!("`{}` has two values named {} in Rust", name, variant));
+            }
+        }
+
+        self.at(span);
+        let line = self.origin;
+        self.type_line("#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]", line);
+        self.type_line(&format!("{}enum {} {{", self.visibility(), name), line);
+        for (value, variant) in values.iter().zip(&variants) {
+            self.type_line(&format!("    #[serde(rename = {})]", rust_str(value)), line);
+            self.type_line(&format!("    {},", variant), line);
+        }
+        self.type_line("}", line);
+        self.type_line("", 0);
+
+        let quoted: Vec<String> = values.iter().map(|value| rust_str(value)).collect();
+        self.type_line(&format!("impl crate::Validate for {} {{", name), line);
+        self.type_line(
+            "    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {",
+            line,
+        );
+        self.type_line(
+            &format!("        crate::validate_variant(value, path, errors, &[{}]);", quoted.join(", ")),
+            line,
+        );
+        self.type_line("    }", line);
+        self.type_line("}", line);
+        self.type_line("", 0);
+
+        self.type_line(&format!("impl std::fmt::Display for {} {{", name), line);
+        self.type_line("    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {", line);
+        self.type_line("        f.write_str(match self {", line);
+        for (value, variant) in quoted.iter().zip(&variants) {
+            self.type_line(&format!("            {}::{} => {},", name, variant, value), line);
+        }
+        self.type_line("        })", line);
+        self.type_line("    }", line);
+        self.type_line("}", line);
+        self.type_line("", 0);
+
+        self.enums.insert(name.to_string(), values);
+    }
+
+    /// The Rust type of a TS type. `name` is what an inline object type or
+    /// union of strings is declared as, they can't be lowered without one.
+    fn rust_type(&mut self, ty: &TSType, name: Option<&str>) -> String {
+        match ty {
+            TSType::TSStringKeyword(_) => "String".to_string(),
+            TSType::TSNumberKeyword(_) => "f64".to_string(),
+            TSType::TSBooleanKeyword(_) => "bool".to_string(),
+            TSType::TSAnyKeyword(_) | TSType::TSUnknownKeyword(_) => "serde_json::Value".to_string(),
+            TSType::TSVoidKeyword(_) => "()".to_string(),
+            TSType::TSParenthesizedType(inner) => self.rust_type(&inner.type_annotation, name),
+            TSType::TSArrayType(array) => format!("Vec<{}>", self.rust_type(&array.element_type, name)),
+            TSType::TSUnionType(union) => self.union_type(union, name),
+            TSType::TSLiteralType(literal) => match (&literal.literal, name) {
+                (TSLiteral::StringLiteral(value), Some(name)) => {
+                    self.declare_enum(name, literal.span, vec![value.value.to_string()]);
+                    name.to_string()
+                }
+                _ => {
+                    self.error(literal.span, format!("the type `{}` is not supported", self.text(literal.span)));
+                    "serde_json::Value".to_string()
+                }
+            },
+            TSType::TSTypeReference(reference) => {
+                let type_name = match &reference.type_name {
+                    TSTypeName::IdentifierReference(id) => id.name.as_str(),
+                    _ => {
+                        self.error(reference.span, format!("the type `{}` is not supported", self.text(reference.span)));
+                        return "serde_json::Value".to_string();
+                    }
+                };
+                let arguments = reference.type_arguments.as_ref().map(|arguments| &arguments.params);
+                match (type_name, arguments) {
+                    ("Array", Some(arguments)) if arguments.len() == 1 => {
+                        format!("Vec<{}>", self.rust_type(&arguments[0], name))
+                    }
+                    ("Record", Some(arguments)) if arguments.len() == 2 => {
+                        if !matches!(arguments[0], TSType::TSStringKeyword(_)) {
+                            self.error(arguments[0].span(), "the keys of a `Record` must be `string`");
+                        }
+                        format!("std::collections::HashMap<String, {}>", self.rust_type(&arguments[1], name))
+                    }
+                    ("Date", None) => "chrono::DateTime<chrono::Utc>".to_string(),
+                    (_, Some(_)) => {
+                        self.error(reference.span, format!("the type `{}` is not supported", self.text(reference.span)));
+                        "serde_json::Value".to_string()
+                    }
+                    (type_name, None) => type_name.to_string(),
+                }
+            }
+            TSType::TSTypeLiteral(literal) => match name {
+                Some(name) => {
+                    self.declare_struct(name, literal.span, &literal.members);
+                    name.to_string()
+                }
+                None => {
+                    self.error(literal.span, "object types need a name here, declare them with `type`");
+                    "serde_json::Value".to_string()
+                }
+            },
+            other => {
+                self.error(other.span(), format!("the type `{}` is not supported", self.text(other.span())));
+                "serde_json::Value".to_string()
+            }
+        }
+    }
+
+    /// `T | null` and `T | undefined` are `Option<T>`, a union of strings is
+    /// an enum. Other unions have no Rust equivalent.
+    fn union_type(&mut self, union: &TSUnionType, name: Option<&str>) -> String {
+        let (nullable, types): (Vec<&TSType>, Vec<&TSType>) = union
+            .types
+            .iter()
+            .partition(|ty| matches!(ty, TSType::TSNullKeyword(_) | TSType::TSUndefinedKeyword(_)));
+
+        let values: Option<Vec<String>> = types
+            .iter()
+            .map(|ty| match ty {
+                TSType::TSLiteralType(literal) => match &literal.literal {
+                    TSLiteral::StringLiteral(value) => Some(value.value.to_string()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+
+        let rust_type = match (types.as_slice(), values, name) {
+            ([ty], _, _) => self.rust_type(ty, name),
+            (_, Some(values), Some(name)) => {
+                self.declare_enum(name, union.span, values);
+                name.to_string()
+            }
+            (_, Some(_), None) => {
+                self.error(union.span, "unions of strings need a name here, declare them with `type`");
+                "String".to_string()
+            }
+            _ => {
+                self.error(
+                    union.span,
+                    "only unions of string literals and `T | null` are supported",
+                );
+                "serde_json::Value".to_string()
+            }
+        };
+
+        if nullable.is_empty() {
+            rust_type
+        } else {
+            format!("Option<{}>", rust_type)
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        let Some(id) = &function.id else {
+            return;
+        };
+        let name = id.name.as_str();
+        let Some(body) = &function.body else {
+            self.error(function.span, format!("function `{}` has no body", name));
+            return;
+        };
+        if function.r#async || function.generator {
+            self.error(function.span, "async functions and generators are not supported");
+            return;
+        }
+        if function.params.rest.is_some() {
+            self.error(function.params.span, "rest parameters are not supported");
+        }
+
+        let return_type = function.return_type.as_ref().map(|t| &t.type_annotation);
+        self.at(function.span);
+        self.mutated = Mutations::of(body);
+        self.locals.clear();
+        self.returns = None;
+
+        if self.is_handler(name) {
+            let returns_response = match return_type {
+                Some(TSType::TSStringKeyword(_)) => true,
+                Some(TSType::TSTypeReference(reference)) => {
+                    matches!(&reference.type_name, TSTypeName::IdentifierReference(id) if id.name == "Response")
+                }
+                _ => false,
+            };
+            if !function.params.items.is_empty() || !returns_response {
+                self.error(
+                    function.span,
+                    format!("handler `{}` must take no arguments and return string or Response", name),
+                );
+            }
+
+            self.line(&format!(
+                "pub fn {}(db: &crate::PgConnection, req: crate::UbiRequest) -> Result<crate::UbiResponse, crate::UbiError> {{",
+                name
+            ));
+            self.block(Scope::Handler, &body.statements);
+            self.at(Span::new(body.span.end - 1, body.span.end));
+            if !matches!(body.statements.last(), Some(Statement::ReturnStatement(_))) {
+                self.indent += 1;
+                self.line("Ok(crate::UbiResponse::new())");
+                self.indent -= 1;
+            }
+        } else {
+            let signature = self.functions.get(name).cloned().unwrap_or_else(|| self.signature(function));
+            let mut params = Vec::new();
+            for (param, rust_type) in &signature.params {
+                let binding = if self.mutated.contains(param.as_str()) { "mut " } else { "" };
+                params.push(format!("{}{}: {}", binding, rust_ident(param), rust_type));
+                self.locals.insert(param.clone(), rust_type.clone());
+            }
+            let returns = match &signature.returns {
+                Some(rust_type) => format!(" -> {}", rust_type),
+                None => String::new(),
+            };
+            self.returns = signature.returns;
+            self.line(&format!(
+                "{}fn {}({}){} {{",
+                self.visibility(),
+                rust_ident(name),
+                params.join(", "),
+                returns
+            ));
+            self.block(Scope::Function, &body.statements);
+            self.at(Span::new(body.span.end - 1, body.span.end));
+        }
+
+        self.line("}");
+        self.origin = 0;
+        self.line("");
+    }
+
+    fn signature(&mut self, function: &Function) -> Signature {
+        let mut params = Vec::new();
+        for param in &function.params.items {
+            let Some(param_name) = param.pattern.get_identifier_name() else {
+                self.error(param.span, "destructuring parameters is not supported");
+                continue;
+            };
+            let Some(annotation) = &param.type_annotation else {
+                self.error(param.span, format!("parameter `{}` needs a type", param_name));
+                continue;
+            };
+            let rust_type = self.rust_type(&annotation.type_annotation, None);
+            params.push((param_name.to_string(), rust_type));
+        }
+
+        let returns = match function.return_type.as_ref().map(|t| &t.type_annotation) {
+            None | Some(TSType::TSVoidKeyword(_)) => None,
+            Some(ty) => Some(self.rust_type(ty, None)),
+        };
+        Signature { params, returns }
+    }
+
+    /// Lowers `statements` one level deeper than the current line.
+    fn block(&mut self, scope: Scope, statements: &[Statement]) {
+        let outer = std::mem::replace(&mut self.scope, scope);
+        self.indent += 1;
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.indent -= 1;
+        self.scope = outer;
+    }
+
+    /// The body of an `if`, with or without braces.
+    fn body(&mut self, statement: &Statement) {
+        match statement {
+            Statement::BlockStatement(block) => self.block(self.scope, &block.body),
+            other => self.block(self.scope, std::slice::from_ref(other)),
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        self.at(statement.span());
+        match statement {
+            Statement::VariableDeclaration(declaration) => self.variable_declaration(declaration),
+            Statement::ExpressionStatement(statement) => self.expression_statement(&statement.expression),
+            Statement::ReturnStatement(statement) => self.return_statement(statement),
+            Statement::IfStatement(statement) => self.if_statement(statement, ""),
+            Statement::ForStatement(statement) => self.for_statement(statement),
+            Statement::ForOfStatement(statement) => self.for_of_statement(statement),
+            Statement::WhileStatement(statement) => {
+                let test = self.expression(&statement.test);
+                self.line(&format!("while {} {{", test));
+                self.loop_body(&statement.body, None);
+                self.line("}");
+            }
+            Statement::DoWhileStatement(statement) => {
+                let test = self.expression(&statement.test);
+                let check = format!("if !({}) {{ break; }}", test);
+                self.line("loop {");
+                self.loop_body(&statement.body, Some(check));
+                self.line("}");
+            }
+            Statement::SwitchStatement(statement) => self.switch_statement(statement),
+            Statement::BreakStatement(statement) => {
+                if statement.label.is_some() {
+                    self.error(statement.span, "labels are not supported");
+                }
+                match self.jumps.last() {
+                    Some(Jump::Loop { .. }) => self.line("break;"),
+                    Some(Jump::Switch) => {
+                        self.error(statement.span, "`break` can only be the last statement of a `case`")
+                    }
+                    None => self.error(statement.span, "`break` outside of a loop"),
+                }
+            }
+            Statement::ContinueStatement(statement) => {
+                if statement.label.is_some() {
+                    self.error(statement.span, "labels are not supported");
+                }
+                let next = self.jumps.iter().rev().find_map(|jump| match jump {
+                    Jump::Loop { next } => Some(next.clone()),
+                    Jump::Switch => None,
+                });
+                match next {
+                    Some(next) => {
+                        if let Some(next) = next {
+                            self.line(&next);
+                        }
+                        self.line("continue;");
+                    }
+                    None => self.error(statement.span, "`continue` outside of a loop"),
+                }
+            }
+            Statement::BlockStatement(block) => {
+                self.line("{");
+                self.block(self.scope, &block.body);
+                self.line("}");
+            }
+            Statement::EmptyStatement(_) => {}
+            Statement::FunctionDeclaration(function) => {
+                self.error(function.span, "functions can only be declared at the top level of a route file")
+            }
+            Statement::TSTypeAliasDeclaration(_) | Statement::TSInterfaceDeclaration(_) => {
+                self.error(statement.span(), "types can only be declared at the top level of a route file")
+            }
+            other => {
+                let what = match other {
+                    Statement::ForInStatement(_) => "`for...in` loops are",
+                    Statement::LabeledStatement(_) => "labels are",
+                    Statement::TryStatement(_) => "`try` statements are",
+                    Statement::ThrowStatement(_) => "`throw` statements are",
+                    _ => "this statement is",
+                };
+                self.error(other.span(), format!("{} not supported", what));
+            }
+        }
+    }
+
+    fn variable_declaration(&mut self, declaration: &VariableDeclaration) {
+        for declarator in &declaration.declarations {
+            let Some(name) = declarator.id.get_identifier_name() else {
+                self.error(declarator.span, "destructuring is not supported");
+                continue;
+            };
+            // `const` doesn't stop an array from being pushed to, and a `let`
+            // that is never reassigned doesn't need to be `mut`.
+            let keyword = if self.mutated.contains(name.as_str()) { "let mut" } else { "let" };
+            let local = name.to_string();
+            let name = rust_ident(&name);
+            let rust_type = declarator
+                .type_annotation
+                .as_ref()
+                .map(|annotation| self.rust_type(&annotation.type_annotation, None));
+
+            let Some(init) = &declarator.init else {
+                match rust_type {
+                    Some(rust_type) => {
+                        self.line(&format!("{} {}: {};", keyword, name, rust_type));
+                        self.locals.insert(local.clone(), rust_type);
+                    }
+                    None => self.error(declarator.span, format!("`{}` needs a type or a value", name)),
+                }
+                continue;
+            };
+
+            let is_query = matches!(init, Expression::CallExpression(call)
+                if member_path(&call.callee).as_deref() == Some(&["ubi", "query"][..]));
+            let is_data = member_path(init).as_deref() == Some(&["ubi", "req", "data"][..]);
+            if is_data && rust_type.is_none() {
+                self.error(
+                    init.span(),
+                    "`ubi.req.data` needs the type of the body, for example `let body: CreateUser = ubi.req.data`",
+                );
+                continue;
+            }
+
+            let value = match (rust_type.as_deref(), request_type(init)) {
+                // A missing query parameter or header reads as "" or 0 when
+                // the variable isn't declared optional.
+                (Some(declared), Some(given)) if option_inner(&given) == Some(declared) => {
+                    format!("{}.unwrap_or_default()", self.expression(init))
+                }
+                (Some(declared), Some(given)) if declared != given => {
+                    let reads_query = matches!(init, Expression::CallExpression(call)
+                        if matches!(member_path(&call.callee).as_deref(), Some(["ubi", "req", "query" | "queryAll"])));
+                    let hint = if reads_query { ", give the type it is read as, like `ubi.req.query<number>(\"page\")`" } else { "" };
+                    self.error(init.span(), format!("this is `{}`, it can't be assigned to `{}`{}", given, declared, hint));
+                    continue;
+                }
+                _ => self.value(init, rust_type.as_deref()),
+            };
+            if let Some(local_type) = rust_type.clone().or_else(|| self.type_of(init)) {
+                self.locals.insert(local.clone(), local_type);
+            }
+            match rust_type {
+                // ubi.query always answers JSON rows, the declared type only
+                // documents their shape.
+                Some(_) if is_query => self.line(&format!("{} {} = {};", keyword, name, value)),
+                Some(rust_type) => self.line(&format!("{} {}: {} = {};", keyword, name, rust_type, value)),
+                None => self.line(&format!("{} {} = {};", keyword, name, value)),
+            }
+        }
+    }
+
+    fn expression_statement(&mut self, expression: &Expression) {
+        if let Expression::CallExpression(call) = expression {
+            if member_path(&call.callee).as_deref() == Some(&["ubi", "transaction"][..]) {
+                self.transaction(call);
+                return;
+            }
+        }
+
+        let lowered = self.effect(expression);
+        self.line(&format!("{};", lowered));
+    }
+
+    /// An expression evaluated for its side effects, where `i++` is allowed.
+    fn effect(&mut self, expression: &Expression) -> String {
+        match expression {
+            Expression::UpdateExpression(update) => {
+                let one = self.number(1.0, self.target_type(&update.argument).as_deref());
+                let target = self.simple_target(&update.argument);
+                match update.operator {
+                    UpdateOperator::Increment => format!("{} += {}", target, one),
+                    UpdateOperator::Decrement => format!("{} -= {}", target, one),
+                }
+            }
+            Expression::SequenceExpression(sequence) => sequence
+                .expressions
+                .iter()
+                .map(|expression| self.effect(expression))
+                .collect::<Vec<_>>()
+                .join("; "),
+            other => self.expression(other),
+        }
+    }
+
+    fn transaction(&mut self, call: &CallExpression) {
+        if !matches!(self.scope, Scope::Handler) {
+            self.error(call.span, "`ubi.transaction` can only be used inside a handler, outside of other transactions");
+            return;
+        }
+        let callback = match call.arguments.first().and_then(|a| a.as_expression()) {
+            Some(Expression::ArrowFunctionExpression(callback))
+                if call.arguments.len() == 1 && callback.params.items.is_empty() && !callback.expression =>
+            {
+                callback
+            }
+            _ => {
+                self.error(call.span, "`ubi.transaction` takes a callback without arguments, `ubi.transaction(() => { ... })`");
+                return;
+            }
+        };
+
+        self.line("db.transaction(|| {");
+        // A loop around the transaction can't be left from inside the callback.
+        let jumps = std::mem::take(&mut self.jumps);
+        self.block(Scope::Transaction, &callback.body.statements);
+        self.jumps = jumps;
+        self.indent += 1;
+        self.line("Ok(())");
+        self.indent -= 1;
+        self.line("})?;");
+    }
+
+    fn return_statement(&mut self, statement: &ReturnStatement) {
+        let returns = self.returns.clone();
+        let value = statement
+            .argument
+            .as_ref()
+            .map(|argument| self.typed_expression(argument, returns.as_deref()));
+        match (self.scope, value) {
+            (Scope::Handler, Some(value)) => self.line(&format!("return Ok(crate::UbiResponse::from({}));", value)),
+            (Scope::Handler, None) => self.line("return Ok(crate::UbiResponse::new());"),
+            (Scope::Transaction, _) => self.error(statement.span, "`return` is not supported inside `ubi.transaction`"),
+            (_, Some(value)) => self.line(&format!("return {};", value)),
+            (_, None) => self.line("return;"),
+        }
+    }
+
+    fn if_statement(&mut self, statement: &IfStatement, prefix: &str) {
+        let test = self.expression(&statement.test);
+        self.line(&format!("{}if {} {{", prefix, test));
+        self.body(&statement.consequent);
+        match &statement.alternate {
+            Some(Statement::IfStatement(alternate)) => self.if_statement(alternate, "} else "),
+            Some(alternate) => {
+                self.line("} else {");
+                self.body(alternate);
+                self.line("}");
+            }
+            None => self.line("}"),
+        }
+    }
+
+    /// `for (init; test; update)` becomes a `while` in a block scoping the
+    /// variables of `init`, with `update` at the end of the body and before
+    /// every `continue`.
+    fn for_statement(&mut self, statement: &ForStatement) {
+        let scoped = matches!(statement.init, Some(ForStatementInit::VariableDeclaration(_)));
+        if scoped {
+            self.line("{");
+            self.indent += 1;
+        }
+        match &statement.init {
+            Some(ForStatementInit::VariableDeclaration(declaration)) => self.variable_declaration(declaration),
+            Some(init) => {
+                if let Some(init) = init.as_expression() {
+                    let lowered = self.effect(init);
+                    self.line(&format!("{};", lowered));
+                }
+            }
+            None => {}
+        }
+
+        self.at(statement.span);
+        match &statement.test {
+            Some(test) => {
+                let test = self.expression(test);
+                self.line(&format!("while {} {{", test));
+            }
+            None => self.line("loop {"),
+        }
+        let update = statement
+            .update
+            .as_ref()
+            .map(|update| format!("{};", self.effect(update)));
+        self.loop_body(&statement.body, update);
+        self.line("}");
+
+        if scoped {
+            self.indent -= 1;
+            self.line("}");
+        }
+    }
+
+    fn for_of_statement(&mut self, statement: &ForOfStatement) {
+        if statement.r#await {
+            self.error(statement.span, "`for await` loops are not supported");
+        }
+        let name = match &statement.left {
+            ForStatementLeft::VariableDeclaration(declaration) => {
+                declaration.declarations.first().and_then(|d| d.id.get_identifier_name())
+            }
+            _ => None,
+        };
+        let Some(name) = name else {
+            self.error(
+                statement.left.span(),
+                "a `for...of` loop needs to declare one variable, `for (const item of items)`",
+            );
+            return;
+        };
+
+        // Items are copied out of the array, as they would be read out of it
+        // in TypeScript. Calls already give an array of their own.
+        let items = match &statement.right {
+            Expression::CallExpression(_) => self.expression(&statement.right),
+            other => format!("{}.clone()", self.expression(other)),
+        };
+        let item = self
+            .type_of(&statement.right)
+            .and_then(|items| Some(items.strip_prefix("Vec<")?.strip_suffix('>')?.to_string()));
+        if let Some(item) = item {
+            self.locals.insert(name.to_string(), item);
+        }
+        let binding = if self.mutated.contains(name.as_str()) { "mut " } else { "" };
+        self.line(&format!("for {}{} in {} {{", binding, rust_ident(&name), items));
+        self.loop_body(&statement.body, None);
+        self.line("}");
+    }
+
+    /// The body of a loop. `next` is emitted after it, and before every
+    /// `continue` in it.
+    fn loop_body(&mut self, body: &Statement, next: Option<String>) {
+        self.jumps.push(Jump::Loop { next: next.clone() });
+        self.body(body);
+        self.jumps.pop();
+        if let Some(next) = next {
+            if !diverges(statements_of(body)) {
+                self.indent += 1;
+                self.line(&next);
+                self.indent -= 1;
+            }
+        }
+    }
+
+    /// A `switch` becomes a chain of `if`s comparing the value to the cases,
+    /// so every case has to end with `break`, `return` or `continue` (or be
+    /// empty, to share the statements of the next one).
+    fn switch_statement(&mut self, statement: &SwitchStatement) {
+        let hint = self.type_of(&statement.discriminant);
+        let value = match &statement.discriminant {
+            discriminant @ (Expression::Identifier(_) | Expression::StaticMemberExpression(_)) => {
+                self.expression(discriminant)
+            }
+            discriminant => {
+                let value = self.expression(discriminant);
+                self.line(&format!("let switch_value = {};", value));
+                "switch_value".to_string()
+            }
+        };
+
+        // Cases without statements share the ones of the next case.
+        let mut groups: Vec<(Vec<&Expression>, bool, &[Statement])> = Vec::new();
+        let mut tests = Vec::new();
+        let mut default = false;
+        for (i, case) in statement.cases.iter().enumerate() {
+            match &case.test {
+                Some(test) => tests.push(test),
+                None => default = true,
+            }
+            let last = i + 1 == statement.cases.len();
+            if case.consequent.is_empty() && !last {
+                continue;
+            }
+
+            let mut consequent: &[Statement] = &case.consequent;
+            if let [Statement::BlockStatement(block)] = consequent {
+                consequent = &block.body;
+            }
+            match consequent.last() {
+                Some(Statement::BreakStatement(brk)) if brk.label.is_none() => {
+                    consequent = &consequent[..consequent.len() - 1];
+                }
+                _ if last || diverges(consequent) => {}
+                _ => self.error(
+                    case.span,
+                    "falling through to the next `case` is not supported, end this one with `break`",
+                ),
+            }
+            groups.push((std::mem::take(&mut tests), std::mem::take(&mut default), consequent));
+        }
+
+        // The default case goes last, whatever its place in the switch.
+        let default = groups.iter().position(|(_, default, _)| *default).map(|i| groups.remove(i).2);
+        let mut prefix = "";
+        for (tests, _, consequent) in groups {
+            let conditions: Vec<String> = tests
+                .iter()
+                .map(|test| format!("{} == {}", value, self.comparand(test, hint.as_deref())))
+                .collect();
+            self.line(&format!("{}if {} {{", prefix, conditions.join(" || ")));
+            self.case_body(consequent);
+            prefix = "} else ";
+        }
+        match default {
+            Some(consequent) => {
+                self.line(if prefix.is_empty() { "{" } else { "} else {" });
+                self.case_body(consequent);
+                self.line("}");
+            }
+            None if prefix.is_empty() => {}
+            None => self.line("}"),
+        }
+    }
+
+    fn case_body(&mut self, statements: &[Statement]) {
+        self.jumps.push(Jump::Switch);
+        self.block(self.scope, statements);
+        self.jumps.pop();
+    }
+
+    /// A side of `==` compared to a `rust_type`. String literals stay `&str`
+    /// so they compare with `String`, `&String` and JSON values alike.
+    fn comparand(&mut self, expression: &Expression, rust_type: Option<&str>) -> String {
+        match expression {
+            Expression::StringLiteral(_) if rust_type.is_some_and(|t| self.enums.contains_key(t)) => {
+                self.typed_expression(expression, rust_type)
+            }
+            Expression::StringLiteral(s) => rust_str(&s.value),
+            other => self.typed_expression(other, rust_type),
+        }
+    }
+
+    /// A number literal, an integer when `rust_type` is one.
+    fn number(&self, value: f64, rust_type: Option<&str>) -> String {
+        match rust_type {
+            Some(rust_type) if is_integer(rust_type) && value.fract() == 0.0 => format!("{}", value as i64),
+            _ => format!("{:?}", value),
+        }
+    }
+
+    /// The Rust type of `expression`, when it can be told from the literals,
+    /// variables, fields and functions it is made of.
+    fn type_of(&self, expression: &Expression) -> Option<String> {
+        if let Some(rust_type) = request_type(expression) {
+            return Some(rust_type);
+        }
+        match expression {
+            Expression::NumericLiteral(_) => Some("f64".to_string()),
+            Expression::StringLiteral(_) | Expression::TemplateLiteral(_) => Some("String".to_string()),
+            Expression::BooleanLiteral(_) => Some("bool".to_string()),
+            Expression::Identifier(id) => self.locals.get(id.name.as_str()).cloned(),
+            Expression::ParenthesizedExpression(inner) => self.type_of(&inner.expression),
+            Expression::StaticMemberExpression(member) if member.property.name == "length" => Some("f64".to_string()),
+            Expression::StaticMemberExpression(member) => {
+                self.member_type(&self.type_of(&member.object)?, &member.property.name)
+            }
+            Expression::ComputedMemberExpression(member) => {
+                let object = self.type_of(&member.object)?;
+                let item = object
+                    .strip_prefix("Vec<")
+                    .or_else(|| object.strip_prefix("std::collections::HashMap<String, "))?;
+                Some(item.strip_suffix('>')?.to_string())
+            }
+            Expression::CallExpression(call) => match member_path(&call.callee).as_deref() {
+                Some(["ubi", "query"]) => Some("Vec<serde_json::Value>".to_string()),
+                Some([name]) => self.functions.get(*name)?.returns.clone(),
+                _ => None,
+            },
+            Expression::NewExpression(new) if matches!(&new.callee, Expression::Identifier(id) if id.name == "Date") => {
+                Some("chrono::DateTime<chrono::Utc>".to_string())
+            }
+            Expression::BinaryExpression(binary) if binary.operator.is_arithmetic() => {
+                if binary.operator == BinaryOperator::Addition
+                    && (self.type_of(&binary.left).as_deref() == Some("String")
+                        || self.type_of(&binary.right).as_deref() == Some("String"))
+                {
+                    return Some("String".to_string());
+                }
+                self.operand_type(&binary.left, &binary.right)
+            }
+            Expression::BinaryExpression(_) | Expression::LogicalExpression(_) => Some("bool".to_string()),
+            Expression::UnaryExpression(unary) if unary.operator == UnaryOperator::LogicalNot => {
+                Some("bool".to_string())
+            }
+            Expression::UnaryExpression(unary) => self.type_of(&unary.argument),
+            Expression::ConditionalExpression(conditional) => self
+                .type_of(&conditional.consequent)
+                .or_else(|| self.type_of(&conditional.alternate)),
+            Expression::TSAsExpression(inner) => self.type_of(&inner.expression),
+            _ => None,
+        }
+    }
+
+    /// The type two operands are lowered to, the one of the side that isn't
+    /// a number literal.
+    fn operand_type(&self, left: &Expression, right: &Expression) -> Option<String> {
+        [left, right]
+            .into_iter()
+            .filter(|operand| !matches!(operand, Expression::NumericLiteral(_)))
+            .find_map(|operand| self.type_of(operand))
+    }
+
+    fn member_type(&self, object: &str, property: &str) -> Option<String> {
+        let field = self.structs.get(object)?.iter().find(|field| field.name == property)?;
+        if field.optional {
+            Some(format!("Option<{}>", field.rust_type))
+        } else {
+            Some(field.rust_type.clone())
+        }
+    }
+
+    fn target_type(&self, target: &SimpleAssignmentTarget) -> Option<String> {
+        match target {
+            SimpleAssignmentTarget::AssignmentTargetIdentifier(id) => self.locals.get(id.name.as_str()).cloned(),
+            SimpleAssignmentTarget::StaticMemberExpression(member) => {
+                self.member_type(&self.type_of(&member.object)?, &member.property.name)
+            }
+            SimpleAssignmentTarget::ComputedMemberExpression(member) => {
+                let object = self.type_of(&member.object)?;
+                Some(object.strip_prefix("Vec<")?.strip_suffix('>')?.to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// An expression stored or passed somewhere. Variables and fields are
+    /// cloned unless they are `Copy`, TypeScript shares them where Rust
+    /// would move them.
+    fn value(&mut self, expression: &Expression, rust_type: Option<&str>) -> String {
+        let place = match expression {
+            Expression::Identifier(id) => id.name != "undefined",
+            Expression::StaticMemberExpression(member) => {
+                member.property.name != "length" && member_path(expression).is_none_or(|path| path[0] != "ubi")
+            }
+            Expression::ComputedMemberExpression(_) => true,
+            _ => false,
+        };
+        let copy = self.type_of(expression).is_some_and(|t| self.is_copy(&t));
+        if !place || copy {
+            return self.typed_expression(expression, rust_type);
+        }
+
+        let cloned = format!("{}.clone()", self.expression(expression));
+        let is_option = self.type_of(expression).is_some_and(|t| t.starts_with("Option<"));
+        match rust_type {
+            Some(rust_type) if rust_type.starts_with("Option<") && !is_option => format!("Some({})", cloned),
+            _ => cloned,
+        }
+    }
+
+    fn is_copy(&self, rust_type: &str) -> bool {
+        match option_inner(rust_type) {
+            Some(inner) => self.is_copy(inner),
+            None => matches!(rust_type, "f64" | "bool") || is_integer(rust_type) || self.enums.contains_key(rust_type),
+        }
+    }
+
+    /// Lowers an expression whose Rust type is known, object literals become
+    /// struct literals of that type.
+    fn typed_expression(&mut self, expression: &Expression, rust_type: Option<&str>) -> String {
+        if let Some(inner) = rust_type.and_then(option_inner) {
+            let nullable = matches!(expression, Expression::NullLiteral(_))
+                || matches!(expression, Expression::Identifier(id) if id.name == "undefined");
+            let is_option = self.type_of(expression).is_some_and(|t| t.starts_with("Option<"));
+            return if nullable || is_option {
+                self.expression(expression)
+            } else {
+                format!("Some({})", self.typed_expression(expression, Some(inner)))
+            };
+        }
+
+        match (expression, rust_type) {
+            (Expression::NumericLiteral(n), rust_type) => self.number(n.value, rust_type),
+            (Expression::UnaryExpression(unary), Some(_)) if unary.operator == UnaryOperator::UnaryNegation => {
+                format!("-{}", self.typed_expression(&unary.argument, rust_type))
+            }
+            (Expression::ParenthesizedExpression(inner), Some(_)) => {
+                format!("({})", self.typed_expression(&inner.expression, rust_type))
+            }
+            (Expression::StringLiteral(s), Some(name)) if self.enums.contains_key(name) => {
+                if !self.enums[name].iter().any(|value| *value == s.value.as_str()) {
+                    self.error(s.span, format!("{:?} is not one of the values of {}", s.value.as_str(), name));
+                }
+                format!("{}::{}", name, variant_name(&s.value))
+            }
+            (Expression::ConditionalExpression(conditional), Some(_)) => self.conditional(conditional, rust_type),
+            (Expression::ObjectExpression(object), Some(rust_type))
+                if rust_type.starts_with("std::collections::HashMap<String, ") =>
+            {
+                let value_type = &rust_type["std::collections::HashMap<String, ".len()..rust_type.len() - 1];
+                self.map_literal(object, value_type)
+            }
+            (Expression::ObjectExpression(object), Some(rust_type)) if self.structs.contains_key(rust_type) => {
+                self.struct_literal(rust_type, object)
+            }
+            (Expression::ArrayExpression(array), rust_type) => {
+                let item = rust_type
+                    .and_then(|t| t.strip_prefix("Vec<"))
+                    .and_then(|t| t.strip_suffix('>'));
+                let items: Vec<String> = array
+                    .elements
+                    .iter()
+                    .map(|element| match element.as_expression() {
+                        Some(element) => self.value(element, item),
+                        None => {
+                            self.error(element.span(), "spreads and holes are not supported in arrays");
+                            String::new()
+                        }
+                    })
+                    .collect();
+                format!("vec![{}]", items.join(", "))
+            }
+            (expression, _) => self.expression(expression),
+        }
+    }
+
+    /// An object literal of a `Record`.
+    fn map_literal(&mut self, object: &ObjectExpression, value_type: &str) -> String {
+        let mut entries = Vec::new();
+        for property in &object.properties {
+            let ObjectPropertyKind::ObjectProperty(property) = property else {
+                self.error(property.span(), "spreads are not supported in object literals");
+                continue;
+            };
+            let Some(key) = property_name(&property.key) else {
+                self.error(property.key.span(), "computed property names are not supported");
+                continue;
+            };
+            let value = self.value(&property.value, Some(value_type));
+            entries.push(format!("({}.to_string(), {})", rust_str(&key), value));
+        }
+
+        if entries.is_empty() {
+            "std::collections::HashMap::new()".to_string()
+        } else {
+            format!("std::collections::HashMap::from([{}])", entries.join(", "))
+        }
+    }
+
+    fn struct_literal(&mut self, name: &str, object: &ObjectExpression) -> String {
+        let fields = self.structs.get(name).cloned().unwrap_or_default();
+        let mut values: Vec<Option<String>> = vec![None; fields.len()];
+
+        for property in &object.properties {
+            let ObjectPropertyKind::ObjectProperty(property) = property else {
+                self.error(property.span(), "spreads are not supported in object literals");
+                continue;
+            };
+            let Some(key) = property_name(&property.key) else {
+                self.error(property.key.span(), "computed property names are not supported");
+                continue;
+            };
+            let Some(index) = fields.iter().position(|field| field.name == key) else {
+                self.error(property.key.span(), format!("`{}` is not a field of {}", key, name));
+                continue;
+            };
+
+            let field = &fields[index];
+            let value = match (&property.value, field.optional) {
+                (Expression::NullLiteral(_), true) => "None".to_string(),
+                (value, true) => format!("Some({})", self.value(value, Some(&field.rust_type))),
+                (value, false) => self.value(value, Some(&field.rust_type)),
+            };
+            values[index] = Some(value);
+        }
+
+        let mut parts = Vec::new();
+        for (field, value) in fields.iter().zip(values) {
+            match (value, field.optional) {
+                (Some(value), _) => parts.push(format!("{}: {}", rust_ident(&field.name), value)),
+                (None, true) => parts.push(format!("{}: None", rust_ident(&field.name))),
+                (None, false) => self.error(object.span, format!("missing field `{}` of {}", field.name, name)),
+            }
+        }
+
+        format!("{} {{ {} }}", name, parts.join(", "))
+    }
+
+    fn expression(&mut self, expression: &Expression) -> String {
+        match expression {
+            Expression::StringLiteral(s) => format!("{}.to_string()", rust_str(&s.value)),
+            Expression::TemplateLiteral(template) => self.template(template),
+            Expression::NumericLiteral(n) => self.number(n.value, None),
+            Expression::BooleanLiteral(b) => b.value.to_string(),
+            Expression::NullLiteral(_) => "None".to_string(),
+            Expression::Identifier(id) if id.name == "undefined" => "None".to_string(),
+            Expression::Identifier(id) => rust_ident(&id.name),
+            Expression::ParenthesizedExpression(inner) => format!("({})", self.expression(&inner.expression)),
+            Expression::TSAsExpression(inner) => self.expression(&inner.expression),
+            Expression::TSSatisfiesExpression(inner) => self.expression(&inner.expression),
+            Expression::ArrayExpression(_) => self.typed_expression(expression, None),
+            Expression::ObjectExpression(object) => self.json_object(object),
+            Expression::StaticMemberExpression(member) => self.static_member(member),
+            Expression::ComputedMemberExpression(member) => self.computed_member(member),
+            Expression::CallExpression(call) => self.call(call),
+            Expression::BinaryExpression(binary) => self.binary(expression, binary),
+            Expression::LogicalExpression(logical) => {
+                let operator = match logical.operator {
+                    LogicalOperator::And => "&&",
+                    LogicalOperator::Or => "||",
+                    LogicalOperator::Coalesce => {
+                        self.error(logical.span, "`??` is not supported");
+                        "||"
+                    }
+                };
+                let left = self.expression(&logical.left);
+                let right = self.expression(&logical.right);
+                format!("{} {} {}", left, operator, right)
+            }
+            Expression::UnaryExpression(unary) => {
+                let operator = match unary.operator {
+                    UnaryOperator::LogicalNot => "!",
+                    UnaryOperator::UnaryNegation => "-",
+                    UnaryOperator::BitwiseNot => "!",
+                    _ => {
+                        self.error(unary.span, format!("`{}` is not supported", unary.operator.as_str()));
+                        ""
+                    }
+                };
+                format!("{}{}", operator, self.expression(&unary.argument))
+            }
+            Expression::AssignmentExpression(assignment) => {
+                let target = match &assignment.left {
+                    AssignmentTarget::AssignmentTargetIdentifier(id) => rust_ident(&id.name),
+                    AssignmentTarget::StaticMemberExpression(member) => self.static_member(member),
+                    AssignmentTarget::ComputedMemberExpression(member) => self.computed_member(member),
+                    other => {
+                        self.error(other.span(), "destructuring assignments are not supported");
+                        String::new()
+                    }
+                };
+                let rust_type = assignment
+                    .left
+                    .as_simple_assignment_target()
+                    .and_then(|target| self.target_type(target));
+                // `String` only takes `+= &str`.
+                if assignment.operator == AssignmentOperator::Addition && rust_type.as_deref() == Some("String") {
+                    return format!("{}.push_str({})", target, self.str_argument(&assignment.right));
+                }
+                let value = self.value(&assignment.right, rust_type.as_deref());
+                format!("{} {} {}", target, assignment.operator.as_str(), value)
+            }
+            Expression::UpdateExpression(update) => {
+                self.error(update.span, format!("`{}` can only be used as a statement", update.operator.as_str()));
+                String::new()
+            }
+            Expression::ArrowFunctionExpression(arrow) => {
+                self.error(arrow.span, "closures are only supported as the callback of `ubi.transaction`");
+                String::new()
+            }
+            Expression::ConditionalExpression(conditional) => {
+                let rust_type = self.type_of(expression);
+                self.conditional(conditional, rust_type.as_deref())
+            }
+            Expression::NewExpression(new) => match &new.callee {
+                Expression::Identifier(id) if id.name == "Date" && new.arguments.is_empty() => {
+                    "chrono::Utc::now()".to_string()
+                }
+                _ => {
+                    self.error(new.span, "`new` is only supported as `new Date()`, the current time");
+                    String::new()
+                }
+            },
+            Expression::TSNonNullExpression(non_null) => {
+                self.error(non_null.span, "non-null assertions are not supported");
+                String::new()
+            }
+            other => {
+                self.error(other.span(), format!("`{}` is not supported", self.text(other.span())));
+                String::new()
+            }
+        }
+    }
+
+    fn conditional(&mut self, conditional: &ConditionalExpression, rust_type: Option<&str>) -> String {
+        let test = self.expression(&conditional.test);
+        let consequent = self.typed_expression(&conditional.consequent, rust_type);
+        let alternate = match &conditional.alternate {
+            // `a ? b : c ? d : e` reads as an `else if`.
+            Expression::ConditionalExpression(alternate) => {
+                format!("else {}", self.conditional(alternate, rust_type))
+            }
+            alternate => format!("else {{ {} }}", self.typed_expression(alternate, rust_type)),
+        };
+        format!("if {} {{ {} }} {}", test, consequent, alternate)
+    }
+
+    fn simple_target(&mut self, target: &SimpleAssignmentTarget) -> String {
+        match target {
+            SimpleAssignmentTarget::AssignmentTargetIdentifier(id) => rust_ident(&id.name),
+            SimpleAssignmentTarget::StaticMemberExpression(member) => self.static_member(member),
+            SimpleAssignmentTarget::ComputedMemberExpression(member) => self.computed_member(member),
+            other => {
+                self.error(other.span(), "this can't be incremented");
+                String::new()
+            }
+        }
+    }
+
+    fn template(&mut self, template: &TemplateLiteral) -> String {
+        let mut text = String::new();
+        let mut arguments = Vec::new();
+        for (i, quasi) in template.quasis.iter().enumerate() {
+            let cooked = quasi.value.cooked.as_ref().unwrap_or(&quasi.value.raw);
+            text.push_str(&format_text(cooked));
+            if let Some(expression) = template.expressions.get(i) {
+                text.push_str("{}");
+                arguments.push(self.expression(expression));
+            }
+        }
+
+        if arguments.is_empty() {
+            format!("\"{}\".to_string()", text.replace("{{", "{").replace("}}", "}"))
+        } else {
+            format!("format!(\"{}\", {})", text, arguments.join(", "))
+        }
+    }
+
+    fn binary(&mut self, expression: &Expression, binary: &BinaryExpression) -> String {
+        if binary.operator == BinaryOperator::Addition {
+            let mut operands = Vec::new();
+            addition_operands(expression, &mut operands);
+            let is_text = |operand: &&Expression| {
+                is_string(operand) || self.type_of(operand).as_deref() == Some("String")
+            };
+            if operands.iter().any(is_text) {
+                let mut text = String::new();
+                let mut arguments = Vec::new();
+                for operand in operands {
+                    match operand {
+                        Expression::StringLiteral(s) => text.push_str(&format_text(&s.value)),
+                        other => {
+                            text.push_str("{}");
+                            arguments.push(self.text_operand(other));
+                        }
+                    }
+                }
+                return format!("format!(\"{}\", {})", text, arguments.join(", "));
+            }
+        }
+
+        let operator = match binary.operator {
+            BinaryOperator::StrictEquality => "==",
+            BinaryOperator::StrictInequality => "!=",
+            BinaryOperator::Exponential | BinaryOperator::In | BinaryOperator::Instanceof
+            | BinaryOperator::ShiftRightZeroFill => {
+                self.error(binary.span, format!("`{}` is not supported", binary.operator.as_str()));
+                binary.operator.as_str()
+            }
+            other => other.as_str(),
+        };
+        let rust_type = self.operand_type(&binary.left, &binary.right);
+        let (left, right) = if binary.operator.is_equality() {
+            (
+                self.comparand(&binary.left, rust_type.as_deref()),
+                self.comparand(&binary.right, rust_type.as_deref()),
+            )
+        } else {
+            (
+                self.typed_expression(&binary.left, rust_type.as_deref()),
+                self.typed_expression(&binary.right, rust_type.as_deref()),
+            )
+        };
+        format!("{} {} {}", left, operator, right)
+    }
+
+    fn json_object(&mut self, object: &ObjectExpression) -> String {
+        let mut fields = Vec::new();
+        for property in &object.properties {
+            let ObjectPropertyKind::ObjectProperty(property) = property else {
+                self.error(property.span(), "spreads are not supported in object literals");
+                continue;
+            };
+            let Some(key) = property_name(&property.key) else {
+                self.error(property.key.span(), "computed property names are not supported");
+                continue;
+            };
+            // JSON has one kind of number, `1` stays `1` instead of `1.0`.
+            let value = match &property.value {
+                Expression::NumericLiteral(n) => self.number(n.value, Some("i64")),
+                value => self.expression(value),
+            };
+            fields.push(format!("{}: {}", rust_str(&key), value));
+        }
+        format!("serde_json::json!({{{}}})", fields.join(", "))
+    }
+
+    fn static_member(&mut self, member: &StaticMemberExpression) -> String {
+        if member.optional {
+            self.error(member.span, "optional chaining is not supported");
+        }
+        if let Some(path) = member_path(&member.object) {
+            if path[0] == "ubi" {
+                let mut path = path;
+                path.push(member.property.name.as_str());
+                return self.ubi_member(member.span, &path);
+            }
+        }
+
+        let object = self.expression(&member.object);
+        match member.property.name.as_str() {
+            "length" => format!("({}.len() as f64)", object),
+            property => format!("{}.{}", object, rust_ident(property)),
+        }
+    }
+
+    fn computed_member(&mut self, member: &ComputedMemberExpression) -> String {
+        let object = self.expression(&member.object);
+        let index = match &member.expression {
+            Expression::StringLiteral(s) => rust_str(&s.value),
+            Expression::NumericLiteral(n) => self.number(n.value, Some("usize")),
+            other => format!("({}) as usize", self.expression(other)),
+        };
+        format!("{}[{}]", object, index)
+    }
+
+    /// `ubi.req.method` and the other properties of `ubi` that aren't calls.
+    fn ubi_member(&mut self, span: Span, path: &[&str]) -> String {
+        let lowered = match path {
+            ["ubi", "req", "data"] => "req.data()?",
+            ["ubi", "req", "method"] => "req.method().to_string()",
+            ["ubi", "req", "path"] => "req.path().to_string()",
+            ["ubi", "req", "ip"] => "req.ip().map(str::to_string)",
+            _ => {
+                self.error(span, format!("`{}` is not part of the ubi API", path.join(".")));
+                return String::new();
+            }
+        };
+        self.require_handler(span, path);
+        lowered.to_string()
+    }
+
+    fn require_handler(&mut self, span: Span, path: &[&str]) {
+        if !matches!(self.scope, Scope::Handler | Scope::Transaction) {
+            self.error(span, format!("`{}` can only be used inside a handler", path.join(".")));
+        }
+    }
+
+    /// An argument the runtime takes as `&str`.
+    fn str_argument(&mut self, expression: &Expression) -> String {
+        match expression {
+            Expression::StringLiteral(s) => rust_str(&s.value),
+            other => format!("&{}", self.text_operand(other)),
+        }
+    }
+
+    /// An operand of a string concatenation. An `Option` has no `Display`,
+    /// a missing value adds nothing to the string.
+    fn text_operand(&mut self, expression: &Expression) -> String {
+        let rust_type = self.type_of(expression).unwrap_or_default();
+        let mut code = self.expression(expression);
+        if !rust_type.starts_with("Option<") {
+            return code;
+        }
+        if !matches!(expression, Expression::Identifier(_) | Expression::StaticMemberExpression(_)) {
+            code = format!("({})", code);
+        }
+        if rust_type == "Option<String>" {
+            format!("{}.as_deref().unwrap_or_default()", code)
+        } else {
+            format!("{}.as_ref().map(ToString::to_string).unwrap_or_default()", code)
+        }
+    }
+
+    fn arguments(&mut self, call: &CallExpression) -> Vec<String> {
+        call.arguments
+            .iter()
+            .map(|argument| match argument.as_expression() {
+                Some(expression) => self.expression(expression),
+                None => {
+                    self.error(argument.span(), "spread arguments are not supported");
+                    String::new()
+                }
+            })
+            .collect()
+    }
+
+    fn call(&mut self, call: &CallExpression) -> String {
+        if call.optional {
+            self.error(call.span, "optional chaining is not supported");
+        }
+
+        match member_path(&call.callee).as_deref() {
+            Some(["console", "log"]) => {
+                let arguments = self.arguments(call);
+                format!("println!(\"{}\", {})", vec!["{:?}"; arguments.len()].join(" "), arguments.join(", "))
+            }
+            Some(path) if path[0] == "ubi" => {
+                let path = path.to_vec();
+                self.ubi_call(call, &path)
+            }
+            _ => {
+                let callee = self.expression(&call.callee);
+                let params = match &call.callee {
+                    Expression::Identifier(id) => self.functions.get(id.name.as_str()).map(|f| f.params.clone()),
+                    _ => None,
+                };
+                let arguments = match params {
+                    Some(params) => call
+                        .arguments
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, argument)| {
+                            let rust_type = params.get(i).map(|(_, rust_type)| rust_type.as_str());
+                            argument.as_expression().map(|argument| self.value(argument, rust_type))
+                        })
+                        .collect(),
+                    None => self.arguments(call),
+                };
+                format!("{}({})", callee, arguments.join(", "))
+            }
+        }
+    }
+
+    fn ubi_call(&mut self, call: &CallExpression, path: &[&str]) -> String {
+        let arguments: Vec<&Expression> = call.arguments.iter().filter_map(|a| a.as_expression()).collect();
+        if arguments.len() != call.arguments.len() {
+            self.error(call.span, "spread arguments are not supported");
+            return String::new();
+        }
+
+        let expected = match path {
+            ["ubi", "json"] | ["ubi", "response"] => 1,
+            ["ubi", "query"] => {
+                if arguments.is_empty() || arguments.len() > 2 {
+                    self.error(call.span, "`ubi.query` takes the SQL and optionally an array of parameters");
+                    return String::new();
+                }
+                arguments.len()
+            }
+            ["ubi", "transaction"] => {
+                self.error(call.span, "`ubi.transaction` can only be used as a statement");
+                return String::new();
+            }
+            ["ubi", "req", "params" | "query" | "queryAll" | "header" | "cookie"] => 1,
+            _ => {
+                self.error(call.span, format!("`{}` is not part of the ubi API", path.join(".")));
+                return String::new();
+            }
+        };
+        if arguments.len() != expected {
+            self.error(
+                call.span,
+                format!("`{}` takes {} argument{}", path.join("."), expected, if expected == 1 { "" } else { "s" }),
+            );
+            return String::new();
+        }
+
+        match path {
+            ["ubi", "json"] => format!("serde_json::json!(&{}).to_string()", self.expression(arguments[0])),
+            ["ubi", "response"] => self.response(arguments[0]),
+            ["ubi", "query"] => {
+                self.require_handler(call.span, path);
+                let sql = self.str_argument(arguments[0]);
+                match arguments.get(1) {
+                    None => format!("db.query({}, &[])?", sql),
+                    Some(Expression::ArrayExpression(array)) => {
+                        let mut params = Vec::new();
+                        for element in &array.elements {
+                            match element.as_expression() {
+                                Some(Expression::NullLiteral(_)) => params.push("null".to_string()),
+                                // Cloned like any other value, binding it
+                                // mustn't move it out of the handler.
+                                Some(param) => params.push(self.value(param, None)),
+                                None => self.error(element.span(), "spreads and holes are not supported in arrays"),
+                            }
+                        }
+                        format!("db.query({}, sql_params![{}])?", sql, params.join(", "))
+                    }
+                    Some(other) => {
+                        self.error(other.span(), "the parameters of `ubi.query` must be an array literal");
+                        String::new()
+                    }
+                }
+            }
+            ["ubi", "req", method] => {
+                self.require_handler(call.span, path);
+                let name = self.str_argument(arguments[0]);
+                let typed = call.type_arguments.as_ref().and_then(|arguments| arguments.params.first());
+                match (*method, typed) {
+                    ("query" | "queryAll", Some(ty)) => {
+                        let rust_type = query_type(ty).unwrap_or_else(|| {
+                            self.error(ty.span(), "query parameters can be read as number, string or boolean");
+                            "String"
+                        });
+                        let accessor = if *method == "query" { "query_as" } else { "query_all_as" };
+                        format!("req.{}::<{}>({})?", accessor, rust_type, name)
+                    }
+                    (_, Some(ty)) => {
+                        self.error(ty.span(), format!("`ubi.req.{}` doesn't take a type", method));
+                        String::new()
+                    }
+                    // Owned, like the variables they end up in.
+                    ("queryAll", None) => format!("req.query_all({}).into_iter().cloned().collect::<Vec<_>>()", name),
+                    (method, None) => format!("req.{}({}).cloned()", method, name),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// `ubi.response({ status, headers, cookies, body })` as a chain of
+    /// `UbiResponse` builder calls.
+    fn response(&mut self, argument: &Expression) -> String {
+        let Expression::ObjectExpression(object) = argument else {
+            self.error(argument.span(), "`ubi.response` takes an object literal");
+            return String::new();
+        };
+
+        let mut hasil = "crate::UbiResponse::new()".to_string();
+        for property in &object.properties {
+            let ObjectPropertyKind::ObjectProperty(property) = property else {
+                self.error(property.span(), "spreads are not supported in object literals");
+                continue;
+            };
+            let key = property_name(&property.key).unwrap_or_default();
+            match key.as_str() {
+                "status" | "body" => {
+                    let rust_type = if key == "status" { Some("u16") } else { None };
+                    let value = self.typed_expression(&property.value, rust_type);
+                    hasil.push_str(&format!(".{}({})", key, value));
+                }
+                "headers" | "cookies" => {
+                    let Expression::ObjectExpression(entries) = &property.value else {
+                        self.error(property.value.span(), format!("`{}` must be an object literal", key));
+                        continue;
+                    };
+                    let method = if key == "headers" { "header" } else { "cookie" };
+                    for entry in &entries.properties {
+                        let name = match entry {
+                            ObjectPropertyKind::ObjectProperty(entry) => {
+                                property_name(&entry.key).map(|name| (name, &entry.value))
+                            }
+                            _ => None,
+                        };
+                        match name {
+                            Some((name, value)) => {
+                                let value = self.expression(value);
+                                hasil.push_str(&format!(".{}({}, {})", method, rust_str(&name), value));
+                            }
+                            None => self.error(entry.span(), format!("`{}` must map names to values", key)),
+                        }
+                    }
+                }
+                _ => self.error(
+                    property.key.span(),
+                    format!("unknown `ubi.response` field `{}`, expected status, headers, cookies or body", key),
+                ),
+            }
+        }
+
+        hasil
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lowers `source` as a route file, checking it gives exactly `rust`.
+    fn assert_lowers(source: &str, rust: &str) {
+        let lowered = lower("routes/server.ts", source, &HashMap::new()).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(lowered.rust.trim(), rust.trim(), "\n{}", lowered.rust);
+    }
+
+    fn lower_error(source: &str) -> String {
+        match lower("routes/server.ts", source, &HashMap::new()) {
+            Ok(lowered) => panic!("lowered to\n{}", lowered.rust),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn if_else() {
+        assert_lowers(
+            r#"
+function size(n: number): string {
+    if (n > 10) {
+        return "big"
+    } else if (n > 0) {
+        return "small"
+    } else {
+        return "none"
+    }
+}
+"#,
+            r#"
+fn size(n: f64) -> String {
+    if n > 10.0 {
+        return "big".to_string();
+    } else if n > 0.0 {
+        return "small".to_string();
+    } else {
+        return "none".to_string();
+    }
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn for_with_break_continue_and_strict_equality() {
+        assert_lowers(
+            r#"
+function sum(): number {
+    let total = 0
+    for (let i = 0; i < 10; i++) {
+        if (i === 3) {
+            continue
+        }
+        if (i !== 8) {
+            total += i
+        } else {
+            break
+        }
+    }
+    return total
+}
+"#,
+            r#"
+fn sum() -> f64 {
+    let mut total = 0.0;
+    {
+        let mut i = 0.0;
+        while i < 10.0 {
+            if i == 3.0 {
+                i += 1.0;
+                continue;
+            }
+            if i != 8.0 {
+                total += i;
+            } else {
+                break;
+            }
+            i += 1.0;
+        }
+    }
+    return total;
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn for_of() {
+        assert_lowers(
+            r#"
+function join(names: string[]): string {
+    let all = ""
+    for (const name of names) {
+        all += name
+    }
+    return all
+}
+"#,
+            r#"
+fn join(names: Vec<String>) -> String {
+    let mut all = "".to_string();
+    for name in names.clone() {
+        all.push_str(&name);
+    }
+    return all;
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn while_and_do_while() {
+        assert_lowers(
+            r#"
+function halve(n: number): number {
+    let steps = 0
+    while (n > 1) {
+        n = n / 2
+        steps++
+    }
+    do {
+        steps--
+    } while (steps > 5)
+    return steps
+}
+"#,
+            r#"
+fn halve(mut n: f64) -> f64 {
+    let mut steps = 0.0;
+    while n > 1.0 {
+        n = n / 2.0;
+        steps += 1.0;
+    }
+    loop {
+        steps -= 1.0;
+        if !(steps > 5.0) { break; }
+    }
+    return steps;
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn switch() {
+        assert_lowers(
+            r#"
+function hours(day: string): number {
+    let hours = 0
+    switch (day) {
+        case "sat":
+        case "sun":
+            hours = 0
+            break
+        case "fri":
+            hours = 6
+            break
+        default:
+            hours = 8
+    }
+    return hours
+}
+"#,
+            r#"
+fn hours(day: String) -> f64 {
+    let mut hours = 0.0;
+    if day == "sat" || day == "sun" {
+        hours = 0.0;
+    } else if day == "fri" {
+        hours = 6.0;
+    } else {
+        hours = 8.0;
+    }
+    return hours;
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn ternary() {
+        assert_lowers(
+            r#"
+function size(n: number): string {
+    const size = n > 10 ? "big" : n > 0 ? "small" : "none"
+    return size
+}
+"#,
+            r#"
+fn size(n: f64) -> String {
+    let size = if n > 10.0 { "big".to_string() } else if n > 0.0 { "small".to_string() } else { "none".to_string() };
+    return size;
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn let_and_const_are_mut_only_when_changed() {
+        assert_lowers(
+            r#"
+function count(): number {
+    const list: number[] = []
+    list.push(1)
+    let fixed = 2
+    let changed = 3
+    changed = changed + fixed
+    return changed
+}
+"#,
+            r#"
+fn count() -> f64 {
+    let mut list: Vec<f64> = vec![];
+    list.push(1.0);
+    let fixed = 2.0;
+    let mut changed = 3.0;
+    changed = changed + fixed;
+    return changed;
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn string_unions_are_enums_and_nullable_types_options() {
+        assert_lowers(
+            r#"
+type Role = "admin" | "member" | "guest"
+type Status = "active"
+type User = { name: string; role: Role; status: Status; nickname?: string; manager: string | null }
+"#,
+            r#"
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+enum Role {
+    #[serde(rename = "admin")]
+    Admin,
+    #[serde(rename = "member")]
+    Member,
+    #[serde(rename = "guest")]
+    Guest,
+}
+
+impl crate::Validate for Role {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        crate::validate_variant(value, path, errors, &["admin", "member", "guest"]);
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Admin => "admin",
+            Role::Member => "member",
+            Role::Guest => "guest",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+enum Status {
+    #[serde(rename = "active")]
+    Active,
+}
+
+impl crate::Validate for Status {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        crate::validate_variant(value, path, errors, &["active"]);
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Status::Active => "active",
+        })
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct User {
+    name: String,
+    role: Role,
+    status: Status,
+    nickname: Option<String>,
+    manager: Option<String>,
+}
+
+impl crate::Validate for User {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        if let Some(object) = crate::validate_object(value, path, errors) {
+            crate::validate_field::<String>(object, path, "name", errors);
+            crate::validate_field::<Role>(object, path, "role", errors);
+            crate::validate_field::<Status>(object, path, "status", errors);
+            crate::validate_field::<Option<String>>(object, path, "nickname", errors);
+            crate::validate_field::<Option<String>>(object, path, "manager", errors);
+        }
+    }
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn arrays_records_and_dates() {
+        assert_lowers(
+            r#"
+type Order = {
+    items: string[];
+    quantities: Array<number>;
+    prices: Record<string, number>;
+    placedAt: Date;
+    tags?: string[];
+}
+"#,
+            r#"
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct Order {
+    items: Vec<String>,
+    quantities: Vec<f64>,
+    prices: std::collections::HashMap<String, f64>,
+    placedAt: chrono::DateTime<chrono::Utc>,
+    tags: Option<Vec<String>>,
+}
+
+impl crate::Validate for Order {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        if let Some(object) = crate::validate_object(value, path, errors) {
+            crate::validate_field::<Vec<String>>(object, path, "items", errors);
+            crate::validate_field::<Vec<f64>>(object, path, "quantities", errors);
+            crate::validate_field::<std::collections::HashMap<String, f64>>(object, path, "prices", errors);
+            crate::validate_field::<chrono::DateTime<chrono::Utc>>(object, path, "placedAt", errors);
+            crate::validate_field::<Option<Vec<String>>>(object, path, "tags", errors);
+        }
+    }
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn inline_object_and_union_types_are_named_after_their_field() {
+        assert_lowers(
+            r#"
+type Customer = {
+    name: string;
+    address: { city: string; zip?: string };
+    status: "new" | "returning";
+}
+"#,
+            r#"
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct CustomerAddress {
+    city: String,
+    zip: Option<String>,
+}
+
+impl crate::Validate for CustomerAddress {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        if let Some(object) = crate::validate_object(value, path, errors) {
+            crate::validate_field::<String>(object, path, "city", errors);
+            crate::validate_field::<Option<String>>(object, path, "zip", errors);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+enum CustomerStatus {
+    #[serde(rename = "new")]
+    New,
+    #[serde(rename = "returning")]
+    Returning,
+}
+
+impl crate::Validate for CustomerStatus {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        crate::validate_variant(value, path, errors, &["new", "returning"]);
+    }
+}
+
+impl std::fmt::Display for CustomerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            CustomerStatus::New => "new",
+            CustomerStatus::Returning => "returning",
+        })
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct Customer {
+    name: String,
+    address: CustomerAddress,
+    status: CustomerStatus,
+}
+
+impl crate::Validate for Customer {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        if let Some(object) = crate::validate_object(value, path, errors) {
+            crate::validate_field::<String>(object, path, "name", errors);
+            crate::validate_field::<CustomerAddress>(object, path, "address", errors);
+            crate::validate_field::<CustomerStatus>(object, path, "status", errors);
+        }
+    }
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn string_variables_are_concatenated_with_format() {
+        assert_lowers(
+            r#"
+function full(first: string, last: string): string {
+    let name = first + " " + last
+    name += "!"
+    return first + last
+}
+"#,
+            r#"
+fn full(first: String, last: String) -> String {
+    let mut name = format!("{} {}", first, last);
+    name.push_str("!");
+    return format!("{}{}", first, last);
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn optional_values_are_concatenated_as_nothing_when_missing() {
+        assert_lowers(
+            r#"
+type User = { name: string; nickname?: string; age?: number }
+function get(): string {
+    let user: User = { name: "a" }
+    let title: string | undefined = ubi.req.query("title")
+    let greeting = "hi " + title + " " + user.nickname
+    greeting += user.age
+    return ubi.json(greeting + ubi.req.ip)
+}
+"#,
+            r#"
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct User {
+    name: String,
+    nickname: Option<String>,
+    age: Option<f64>,
+}
+
+impl crate::Validate for User {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        if let Some(object) = crate::validate_object(value, path, errors) {
+            crate::validate_field::<String>(object, path, "name", errors);
+            crate::validate_field::<Option<String>>(object, path, "nickname", errors);
+            crate::validate_field::<Option<f64>>(object, path, "age", errors);
+        }
+    }
+}
+
+pub fn get(db: &crate::PgConnection, req: crate::UbiRequest) -> Result<crate::UbiResponse, crate::UbiError> {
+    let user: User = User { name: "a".to_string(), nickname: None, age: None };
+    let title: Option<String> = req.query("title").cloned();
+    let mut greeting = format!("hi {} {}", title.as_deref().unwrap_or_default(), user.nickname.as_deref().unwrap_or_default());
+    greeting.push_str(&user.age.as_ref().map(ToString::to_string).unwrap_or_default());
+    return Ok(crate::UbiResponse::from(serde_json::json!(&format!("{}{}", greeting, req.ip().map(str::to_string).as_deref().unwrap_or_default())).to_string()));
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn query_parameters_are_cloned_and_request_values_owned() {
+        assert_lowers(
+            r#"
+type User = { name: string }
+function get(): string {
+    let user: User = { name: "a" }
+    let page: number = ubi.req.query<number>("page")
+    let token: string | undefined = ubi.req.header("x-token")
+    let rows = ubi.query("select $1, $2", [user.name, page])
+    return ubi.json(user)
+}
+"#,
+            r#"
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct User {
+    name: String,
+}
+
+impl crate::Validate for User {
+    fn validate(value: &serde_json::Value, path: &str, errors: &mut Vec<crate::FieldError>) {
+        if let Some(object) = crate::validate_object(value, path, errors) {
+            crate::validate_field::<String>(object, path, "name", errors);
+        }
+    }
+}
+
+pub fn get(db: &crate::PgConnection, req: crate::UbiRequest) -> Result<crate::UbiResponse, crate::UbiError> {
+    let user: User = User { name: "a".to_string() };
+    let page: f64 = req.query_as::<f64>("page")?.unwrap_or_default();
+    let token: Option<String> = req.header("x-token").cloned();
+    let rows = db.query("select $1, $2", sql_params![user.name.clone(), page])?;
+    return Ok(crate::UbiResponse::from(serde_json::json!(&user).to_string()));
+}
+"#,
+        );
+    }
+
+    #[test]
+    fn request_value_of_another_type_is_an_error() {
+        let e = lower_error("function get(): string {\n    let page: number = ubi.req.query(\"page\")\n    return \"\"\n}\n");
+        assert!(e.contains("routes/server.ts:2:24"), "{}", e);
+        assert!(e.contains("ubi.req.query<number>"), "{}", e);
+    }
+}
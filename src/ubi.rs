@@ -0,0 +1,1313 @@
+//! The `.ubi` component compiler. A component is parsed into a tree of
+//! elements, text, `{expression}` interpolations, `<if>`/`<else-if>`/`<else>`
+//! branches, `<for>` loops, `<script>` and `<style>`, which is then turned into
+//! the HTML of the page and the JavaScript keeping it up to date. Markup that
+//! can't be parsed is reported with its line and column, like the errors of a
+//! server.ts.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::{closing_delimiter, ts};
+
+/// Elements that have no content and no closing tag.
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+lazy_static! {
+    static ref FOR_EACH: Regex = Regex::new(r"(?s)^([A-Za-z_$][\w$]*)\s+in\s+(.+)$").unwrap();
+    static ref FOR_RANGE: Regex = Regex::new(r"(?s)^([A-Za-z_$][\w$]*)\s*=\s*(.+)$").unwrap();
+    static ref FOR_KEY: Regex = Regex::new(r"(?s)^(.*?)\s+key\s*=\s*(.+)$").unwrap();
+}
+
+pub struct Component {
+    pub nodes: Vec<Node>,
+}
+
+pub enum Node {
+    Element(Element),
+    Text(String),
+    Interpolation(Expression),
+    Comment(String),
+    If(If),
+    For(For),
+    Script(Raw),
+    Style(Raw),
+}
+
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<Attribute>,
+    pub children: Vec<Node>,
+}
+
+pub struct Attribute {
+    pub name: String,
+    /// `None` for attributes written without a value, like `disabled`.
+    pub value: Option<Vec<Piece>>,
+    pub start: usize,
+}
+
+/// A part of an attribute value, `"/users/{id}"` is the text `/users/`
+/// followed by the expression `id`.
+pub enum Piece {
+    Text(String),
+    Expression(Expression),
+}
+
+/// JavaScript written in the markup, `start` is where it begins in the
+/// component.
+pub struct Expression {
+    pub code: String,
+    pub start: usize,
+}
+
+/// An `<if>` and the `<else-if>`s and `<else>` following it.
+pub struct If {
+    pub branches: Vec<Branch>,
+}
+
+pub struct Branch {
+    /// `None` for `<else>`.
+    pub condition: Option<Expression>,
+    pub children: Vec<Node>,
+}
+
+/// `<for item in items>` or `<for i = count>`, optionally followed by
+/// `key={item.id}`.
+pub struct For {
+    pub item: String,
+    pub source: ForSource,
+    /// Tells the rows apart when the items change, the index when missing.
+    pub key: Option<Expression>,
+    pub children: Vec<Node>,
+}
+
+pub enum ForSource {
+    Each(Expression),
+    Range(Expression),
+}
+
+/// A `<script>` or `<style>`, its content is not markup.
+pub struct Raw {
+    pub attributes: Vec<Attribute>,
+    pub content: String,
+}
+
+pub struct Error {
+    pub offset: usize,
+    pub message: String,
+}
+
+fn error<T>(offset: usize, message: String) -> Result<T, Error> {
+    Err(Error { offset, message })
+}
+
+/// Compiles a component into the HTML served for its route, the generated
+/// JavaScript is appended as a `<script>`.
+pub fn compile(file_name: &str, source: &str) -> Result<String, String> {
+    let render = |e: Error| ts::render_error(file_name, source, e.offset, e.message);
+    let component = parse(source).map_err(render)?;
+
+    let mut script = String::new();
+    scripts(&component.nodes, &mut script);
+    let mut compiler = Compiler {
+        signals: signals(&script),
+        html: String::new(),
+        js: String::new(),
+    };
+    compiler.markup(&component.nodes).map_err(render)?;
+
+    Ok(format!("{}<script>{}{}</script>", compiler.html, script, compiler.js))
+}
+
+pub fn parse(source: &str) -> Result<Component, Error> {
+    let mut parser = Parser { source, pos: 0 };
+    Ok(Component { nodes: parser.nodes(None)? })
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Nodes up to the closing tag of `parent`, or to the end of the component.
+    fn nodes(&mut self, parent: Option<(&str, usize)>) -> Result<Vec<Node>, Error> {
+        let mut nodes = Vec::new();
+
+        loop {
+            let rest = self.rest();
+
+            if rest.is_empty() {
+                return match parent {
+                    Some((name, start)) => error(start, format!("`<{}>` is never closed", name)),
+                    None => Ok(nodes),
+                };
+            }
+
+            if rest.starts_with("</") {
+                let start = self.pos;
+                let name = self.closing_tag()?;
+                return match parent {
+                    Some((parent, _)) if parent.eq_ignore_ascii_case(name) => Ok(nodes),
+                    Some((parent, _)) => {
+                        error(start, format!("expected `</{}>`, found `</{}>`", parent, name))
+                    }
+                    None => error(start, format!("`</{}>` has no opening tag", name)),
+                };
+            }
+
+            if rest.starts_with("<!--") {
+                let Some(end) = rest.find("-->") else {
+                    return error(self.pos, "comment is never closed".to_string());
+                };
+                nodes.push(Node::Comment(rest[4..end].to_string()));
+                self.pos += end + 3;
+            } else if rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+                self.tag(&mut nodes)?;
+            } else if rest.starts_with('{') {
+                let Some(close) = closing_delimiter(self.source, self.pos) else {
+                    return error(self.pos, "`{` is never closed".to_string());
+                };
+                nodes.push(Node::Interpolation(Expression {
+                    code: self.source[self.pos + 1..close].trim().to_string(),
+                    start: self.pos + 1,
+                }));
+                self.pos = close + 1;
+            } else {
+                // A `<` that doesn't start a tag is text.
+                let len = rest[1..].find(['<', '{']).map_or(rest.len(), |i| i + 1);
+                match nodes.last_mut() {
+                    Some(Node::Text(text)) => text.push_str(&rest[..len]),
+                    _ => nodes.push(Node::Text(rest[..len].to_string())),
+                }
+                self.pos += len;
+            }
+        }
+    }
+
+    fn name(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.')))
+            .unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    /// Reads `</name>`, returning the name.
+    fn closing_tag(&mut self) -> Result<&'a str, Error> {
+        let start = self.pos;
+        self.pos += 2;
+        let name = self.name();
+        self.skip_whitespace();
+        if !self.rest().starts_with('>') {
+            return error(start, format!("`</{}` is never closed", name));
+        }
+        self.pos += 1;
+        Ok(name)
+    }
+
+    /// Reads a tag starting at `<`, with its content and closing tag, into `nodes`.
+    fn tag(&mut self, nodes: &mut Vec<Node>) -> Result<(), Error> {
+        let start = self.pos;
+        self.pos += 1;
+        let name = self.name();
+        let lowercase = name.to_ascii_lowercase();
+
+        match lowercase.as_str() {
+            "if" => {
+                let condition = self.condition(name, start)?;
+                let children = self.nodes(Some((name, start)))?;
+                nodes.push(Node::If(If {
+                    branches: vec![Branch { condition: Some(condition), children }],
+                }));
+            }
+            "else-if" | "else" => {
+                let condition = if lowercase == "else" {
+                    self.skip_whitespace();
+                    if !self.rest().starts_with('>') {
+                        return error(self.pos, "`<else>` doesn't take a condition".to_string());
+                    }
+                    self.pos += 1;
+                    None
+                } else {
+                    Some(self.condition(name, start)?)
+                };
+                let children = self.nodes(Some((name, start)))?;
+
+                // Whitespace and comments may separate the branches.
+                let previous = nodes.iter_mut().rev().find(|node| match node {
+                    Node::Text(text) => !text.trim().is_empty(),
+                    Node::Comment(_) => false,
+                    _ => true,
+                });
+                match previous {
+                    Some(Node::If(group)) if group.branches.last().is_some_and(|b| b.condition.is_some()) => {
+                        group.branches.push(Branch { condition, children });
+                    }
+                    _ => {
+                        return error(start, format!("`<{}>` has to follow an `<if>` or `<else-if>`", name));
+                    }
+                }
+            }
+            "for" => {
+                let header = self.header(name, start)?;
+                let mut trimmed = header.trim();
+                let offset = self.pos - header.len() - 1 + (header.len() - header.trim_start().len());
+                let key = FOR_KEY.captures(trimmed).map(|captures| {
+                    let key = captures.get(2).unwrap();
+                    trimmed = captures.get(1).unwrap().as_str();
+                    expression(key.as_str(), offset + key.start())
+                });
+                let (item, source) = if let Some(captures) = FOR_EACH.captures(trimmed) {
+                    let iterable = captures.get(2).unwrap();
+                    (captures[1].to_string(), ForSource::Each(expression(iterable.as_str(), offset + iterable.start())))
+                } else if let Some(captures) = FOR_RANGE.captures(trimmed) {
+                    let count = captures.get(2).unwrap();
+                    (captures[1].to_string(), ForSource::Range(expression(count.as_str(), offset + count.start())))
+                } else {
+                    return error(start, "expected `<for item in items>` or `<for i = count>`".to_string());
+                };
+                let children = self.nodes(Some((name, start)))?;
+                nodes.push(Node::For(For { item, source, key, children }));
+            }
+            _ => {
+                let (attributes, self_closing) = self.attributes(name, start)?;
+
+                if !self_closing && (lowercase == "script" || lowercase == "style") {
+                    let close = format!("</{}", lowercase);
+                    let Some(len) = self.rest().to_ascii_lowercase().find(&close) else {
+                        return error(start, format!("`<{}>` is never closed", name));
+                    };
+                    let content = self.rest()[..len].to_string();
+                    self.pos += len;
+                    self.closing_tag()?;
+                    let raw = Raw { attributes, content };
+                    nodes.push(if lowercase == "script" { Node::Script(raw) } else { Node::Style(raw) });
+                    return Ok(());
+                }
+
+                let children = if self_closing || VOID_ELEMENTS.contains(&lowercase.as_str()) {
+                    Vec::new()
+                } else {
+                    self.nodes(Some((name, start)))?
+                };
+                nodes.push(Node::Element(Element {
+                    name: name.to_string(),
+                    attributes,
+                    children,
+                }));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The text up to the `>` closing the tag starting at `start`, a `>` in
+    /// brackets or strings doesn't close it.
+    fn header(&mut self, name: &str, start: usize) -> Result<&'a str, Error> {
+        let rest = self.rest();
+        let mut depth = 0;
+        let mut quote: Option<char> = None;
+        let mut escaped = false;
+
+        for (i, c) in rest.char_indices() {
+            if let Some(q) = quote {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+
+            match c {
+                '"' | '\'' | '`' => quote = Some(c),
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth -= 1,
+                '>' if depth <= 0 => {
+                    // `a > b` would end the tag at its `>` and show the rest
+                    // of the comparison as text.
+                    let spaced = |c: Option<char>| matches!(c, Some(' ' | '\t'));
+                    let after = rest[i + 1..].chars().next();
+                    if after == Some('=') || (spaced(rest[..i].chars().next_back()) && spaced(after)) {
+                        return error(
+                            self.pos + i,
+                            format!("`>` ends `<{}`, write the comparison in parentheses or braces, like `{{a > b}}`", name),
+                        );
+                    }
+                    self.pos += i + 1;
+                    return Ok(&rest[..i]);
+                }
+                _ => {}
+            }
+        }
+
+        error(start, format!("`<{}` is never closed", name))
+    }
+
+    fn condition(&mut self, name: &str, start: usize) -> Result<Expression, Error> {
+        let header = self.header(name, start)?;
+        let offset = self.pos - header.len() - 1 + (header.len() - header.trim_start().len());
+        let condition = expression(header.trim(), offset);
+        if condition.code.is_empty() {
+            return error(start, format!("`<{}>` needs a condition", name));
+        }
+        Ok(condition)
+    }
+
+    /// Reads the attributes of a tag up to its `>` or `/>`, telling which one
+    /// closed it.
+    fn attributes(&mut self, tag: &str, start: usize) -> Result<(Vec<Attribute>, bool), Error> {
+        let mut attributes = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                return Ok((attributes, true));
+            }
+            if rest.starts_with('>') {
+                self.pos += 1;
+                return Ok((attributes, false));
+            }
+            let Some(first) = rest.chars().next() else {
+                return error(start, format!("`<{}` is never closed", tag));
+            };
+
+            let len = rest
+                .char_indices()
+                .find(|&(i, c)| c.is_whitespace() || c == '=' || c == '>' || rest[i..].starts_with("/>"))
+                .map_or(rest.len(), |(i, _)| i);
+            let name = &rest[..len];
+            let attribute_start = self.pos;
+            if name.is_empty() || name.contains(['"', '\'', '<', '{', '}']) {
+                return error(self.pos, format!("unexpected `{}` in `<{}>`", first, tag));
+            }
+            self.pos += len;
+
+            self.skip_whitespace();
+            let value = if self.rest().starts_with('=') {
+                self.pos += 1;
+                self.skip_whitespace();
+                Some(self.value(name)?)
+            } else {
+                None
+            };
+            attributes.push(Attribute { name: name.to_string(), value, start: attribute_start });
+        }
+    }
+
+    fn value(&mut self, name: &str) -> Result<Vec<Piece>, Error> {
+        let rest = self.rest();
+        let start = self.pos;
+
+        match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let Some(len) = rest[1..].find(quote) else {
+                    return error(start, format!("the value of `{}` is never closed", name));
+                };
+                self.pos += len + 2;
+                let text = &rest[1..len + 1];
+                // `onclick="..."` and the like hold JavaScript, not interpolations.
+                if name.starts_with("on") && !name.contains(':') {
+                    Ok(vec![Piece::Text(text.to_string())])
+                } else {
+                    pieces(text, start + 1)
+                }
+            }
+            Some('{') => {
+                let Some(close) = closing_delimiter(self.source, start) else {
+                    return error(start, "`{` is never closed".to_string());
+                };
+                self.pos = close + 1;
+                Ok(vec![Piece::Expression(Expression {
+                    code: self.source[start + 1..close].trim().to_string(),
+                    start: start + 1,
+                })])
+            }
+            _ => {
+                let len = rest.find(|c: char| c.is_whitespace() || c == '>').unwrap_or(rest.len());
+                if len == 0 {
+                    return error(start, format!("`{}=` needs a value", name));
+                }
+                self.pos += len;
+                Ok(vec![Piece::Text(rest[..len].to_string())])
+            }
+        }
+    }
+}
+
+/// An expression written bare or in braces, `count > 0` or `{count > 0}`.
+fn expression(code: &str, start: usize) -> Expression {
+    if code.starts_with('{') && closing_delimiter(code, 0) == Some(code.len() - 1) {
+        let inner = &code[1..code.len() - 1];
+        Expression {
+            code: inner.trim().to_string(),
+            start: start + 1 + inner.len() - inner.trim_start().len(),
+        }
+    } else {
+        Expression { code: code.to_string(), start }
+    }
+}
+
+/// Splits the text of an attribute value on its `{expression}`s, `offset` is
+/// where the text starts in the component.
+fn pieces(text: &str, offset: usize) -> Result<Vec<Piece>, Error> {
+    let mut pieces = Vec::new();
+    let mut last = 0;
+
+    while let Some(open) = text[last..].find('{').map(|i| last + i) {
+        let Some(close) = closing_delimiter(text, open) else {
+            return error(offset + open, "`{` is never closed".to_string());
+        };
+        if last < open {
+            pieces.push(Piece::Text(text[last..open].to_string()));
+        }
+        pieces.push(Piece::Expression(Expression {
+            code: text[open + 1..close].trim().to_string(),
+            start: offset + open + 1,
+        }));
+        last = close + 1;
+    }
+    if last < text.len() || pieces.is_empty() {
+        pieces.push(Piece::Text(text[last..].to_string()));
+    }
+
+    Ok(pieces)
+}
+
+/// Gathers the code of every `<script>` of the component, wherever it is.
+fn scripts(nodes: &[Node], script: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Script(raw) => script.push_str(&raw.content),
+            Node::Element(element) => scripts(&element.children, script),
+            Node::If(group) => {
+                for branch in &group.branches {
+                    scripts(&branch.children, script);
+                }
+            }
+            Node::For(each) => scripts(&each.children, script),
+            _ => {}
+        }
+    }
+}
+
+struct Compiler {
+    /// Names of the signals the script of the component declares.
+    signals: Vec<String>,
+    html: String,
+    js: String,
+}
+
+fn element_id() -> String {
+    format!("a{}", Uuid::new_v4().to_string().replace("-", "_"))
+}
+
+/// `<name attributes>` with the attributes that are plain text, the others
+/// are set by the JavaScript of the component. The element gets `id` when it
+/// doesn't have one already.
+fn open_tag(name: &str, attributes: &[Attribute], id: Option<&str>) -> String {
+    let mut hasil = format!("<{}", name);
+    if let Some(id) = id.filter(|_| static_id(attributes).is_none()) {
+        hasil.push_str(&format!(" id=\"{}\"", id));
+    }
+    for attribute in attributes.iter().filter(|attribute| !is_dynamic(attribute)) {
+        hasil.push_str(&format!(" {}", attribute.name));
+        for piece in attribute.value.iter().flatten() {
+            if let Piece::Text(text) = piece {
+                hasil.push_str(&format!("=\"{}\"", text.replace('"', "&quot;")));
+            }
+        }
+    }
+    // A link whose href is only known at run time gets its listener from the
+    // JavaScript of the component instead.
+    if name.eq_ignore_ascii_case("a")
+        && is_local_link(attributes)
+        && static_attribute(attributes, "href").is_some()
+        && static_attribute(attributes, "onclick").is_none()
+    {
+        hasil.push_str(" onclick=\"handleNavigation(event)\"");
+    }
+    hasil.push('>');
+    hasil
+}
+
+/// Whether `attribute` binds the value of a `<select>`, which is one of its
+/// options and so is set once they are built.
+fn binds_options(element: &Element, attribute: &Attribute) -> bool {
+    element.name.eq_ignore_ascii_case("select") && attribute.name == "bind:value"
+}
+
+/// Escapes `text` to be put in a JavaScript template literal.
+fn template_text(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('`', "\\`").replace("${", "\\${")
+}
+
+impl Compiler {
+    /// The value of an expression, a signal written on its own is read.
+    fn value(&self, expression: &Expression) -> String {
+        if self.signals.contains(&expression.code) {
+            format!("{}.get()", expression.code)
+        } else {
+            expression.code.clone()
+        }
+    }
+
+    /// Markup of the page, the interpolations, branches and loops of which are
+    /// filled in by the JavaScript of the component.
+    fn markup(&mut self, nodes: &[Node]) -> Result<(), Error> {
+        for node in nodes {
+            match node {
+                Node::Element(element) => {
+                    let dynamic: Vec<&Attribute> = element.attributes.iter().filter(|a| is_dynamic(a)).collect();
+                    let id = if dynamic.is_empty() {
+                        None
+                    } else {
+                        Some(static_id(&element.attributes).unwrap_or_else(element_id))
+                    };
+                    self.html.push_str(&open_tag(&element.name, &element.attributes, id.as_deref()));
+                    let mut after_children = String::new();
+                    if let Some(id) = &id {
+                        let var = element_id();
+                        self.js.push_str(&format!("let {var} = document.getElementById({});\n", js_string(id)));
+                        for attribute in dynamic {
+                            let js = self.dynamic_attribute(&var, element, attribute, None, "")?;
+                            if binds_options(element, attribute) {
+                                after_children.push_str(&js);
+                            } else {
+                                self.js.push_str(&js);
+                            }
+                        }
+                        if element.name.eq_ignore_ascii_case("a")
+                            && is_local_link(&element.attributes)
+                            && static_attribute(&element.attributes, "href").is_none()
+                        {
+                            self.js.push_str(&format!("{var}.addEventListener(\"click\", handleNavigation);\n"));
+                        }
+                    }
+                    if !VOID_ELEMENTS.contains(&element.name.to_ascii_lowercase().as_str()) {
+                        self.markup(&element.children)?;
+                        self.html.push_str(&format!("</{}>", element.name));
+                    }
+                    self.js.push_str(&after_children);
+                }
+                Node::Text(text) => self.html.push_str(text),
+                Node::Comment(comment) => self.html.push_str(&format!("<!--{}-->", comment)),
+                Node::Interpolation(expression) => {
+                    if expression.code.is_empty() {
+                        return error(expression.start, "`{}` needs an expression".to_string());
+                    }
+                    // `textNode` of the page swaps the marker for the text.
+                    let id = element_id();
+                    self.html.push_str(&format!("<!--ubi:{id}-->"));
+                    self.js.push_str(&format!("let {id} = textNode(\"{id}\");\n"));
+                    self.js.push_str(&format!("effect(() => {id}.data = String(({}) ?? \"\"));\n", self.value(expression)));
+                }
+                Node::If(group) => self.if_group(group)?,
+                Node::For(each) => self.for_loop(each)?,
+                Node::Script(_) => {}
+                Node::Style(raw) => {
+                    self.html.push_str(&open_tag("style", &raw.attributes, None));
+                    self.html.push_str(&raw.content);
+                    self.html.push_str("</style>");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The branches of an `<if>` are all in the page, each between comment
+    /// markers, and `ifGroup` of the page takes the nodes of the ones whose
+    /// condition doesn't hold out of it.
+    fn if_group(&mut self, group: &If) -> Result<(), Error> {
+        let id = element_id();
+        let mut branches = Vec::new();
+
+        for (i, branch) in group.branches.iter().enumerate() {
+            self.html.push_str(&format!("<!--ubi:{id}_{i}-->"));
+            self.markup(&branch.children)?;
+            self.html.push_str(&format!("<!--/ubi:{id}_{i}-->"));
+            branches.push(format!("range(\"{id}_{i}\")"));
+        }
+
+        let shown = self.choose(group, |i| i.to_string(), "-1".to_string());
+        self.js.push_str(&format!("ifGroup([{}], () => {shown});\n", branches.join(", ")));
+
+        Ok(())
+    }
+
+    /// A chain of `? :` picking `branch(i)` for the first branch of
+    /// the group whose condition holds, `otherwise` when none does. Conditions
+    /// after the one that holds are not evaluated, so a branch can rely on the
+    /// ones before it being false, and the effect running the chain depends on
+    /// the signals of every condition it had to look at.
+    fn choose(&self, group: &If, branch: impl Fn(usize) -> String, otherwise: String) -> String {
+        let mut hasil = String::new();
+
+        for (i, b) in group.branches.iter().enumerate() {
+            match &b.condition {
+                Some(condition) => {
+                    hasil.push_str(&format!("({}) ? {} : ", self.value(condition), branch(i)))
+                }
+                None => return hasil + &branch(i),
+            }
+        }
+
+        hasil + &otherwise
+    }
+
+    /// A `<for>` is filled by `forList` of the page, which builds a row
+    /// of nodes for every item with `row_<id>` and keeps the rows of the items
+    /// that are still there when the list changes.
+    fn for_loop(&mut self, each: &For) -> Result<(), Error> {
+        let id = element_id();
+        let item = &each.item;
+        // The rows go between two comments, inside a `<tbody>` or a
+        // `<select>` they are the rows or options the element expects.
+        self.html.push_str(&format!("<!--ubi:{id}--><!--/ubi:{id}-->"));
+
+        let mut body = String::new();
+        self.build(&each.children, "parent", item, &mut 0, 1, &mut body)?;
+        let (items, key, by_index) = self.list_arguments(each);
+
+        self.js.push_str(&format!(
+            r#"
+let {id} = range("{id}");
+function row_{id}(parent, {item}, i) {{
+{body}}}
+forList({id}, () => {items}, ({item}, i) => {key}, row_{id}, {by_index});
+"#
+        ));
+
+        Ok(())
+    }
+
+    /// The items, key and `byIndex` flag `forList` is called with.
+    fn list_arguments(&self, each: &For) -> (String, String, bool) {
+        let items = match &each.source {
+            ForSource::Each(items) => self.value(items),
+            ForSource::Range(count) => format!("Array.from({{ length: {} }}, (_, i) => i)", self.value(count)),
+        };
+        let key = each.key.as_ref().map_or("i".to_string(), |key| key.code.clone());
+        // Rows showing the index are built again when their item moves.
+        (items, key, uses_index(&each.children))
+    }
+
+    /// JavaScript appending the DOM nodes of `nodes` to the element in the
+    /// variable `parent`. Text goes in text nodes and attributes through
+    /// `setAttribute`, so the values of the items are never read as HTML.
+    fn build(
+        &self,
+        nodes: &[Node],
+        parent: &str,
+        item: &str,
+        count: &mut usize,
+        indent: usize,
+        js: &mut String,
+    ) -> Result<(), Error> {
+        let pad = "    ".repeat(indent);
+
+        for node in nodes {
+            match node {
+                Node::Element(element) => {
+                    *count += 1;
+                    let var = format!("e{}", count);
+                    js.push_str(&format!("{pad}let {var} = document.createElement({});\n", js_string(&element.name)));
+                    let mut after_children = String::new();
+                    for attribute in &element.attributes {
+                        if is_dynamic(attribute) {
+                            let attribute_js = self.dynamic_attribute(&var, element, attribute, Some(item), &pad)?;
+                            if binds_options(element, attribute) {
+                                after_children.push_str(&attribute_js);
+                            } else {
+                                js.push_str(&attribute_js);
+                            }
+                            continue;
+                        }
+                        let value = match &attribute.value {
+                            Some(value) => self.attribute_value(value),
+                            None => "\"\"".to_string(),
+                        };
+                        js.push_str(&format!("{pad}{var}.setAttribute({}, {});\n", js_string(&attribute.name), value));
+                    }
+                    if element.name.eq_ignore_ascii_case("a") && is_local_link(&element.attributes) {
+                        js.push_str(&format!("{pad}{var}.addEventListener(\"click\", handleNavigation);\n"));
+                    }
+                    self.build(&element.children, &var, item, count, indent, js)?;
+                    js.push_str(&after_children);
+                    js.push_str(&format!("{pad}{parent}.append({var});\n"));
+                }
+                Node::Text(text) => js.push_str(&format!("{pad}{parent}.append({});\n", js_string(text))),
+                Node::Interpolation(expression) => {
+                    if expression.code.is_empty() {
+                        return error(expression.start, "`{}` needs an expression".to_string());
+                    }
+                    // The text follows the signals it shows on its own, without
+                    // building the row again.
+                    *count += 1;
+                    let var = format!("t{}", count);
+                    js.push_str(&format!("{pad}let {var} = document.createTextNode(\"\");\n"));
+                    js.push_str(&format!("{pad}effect(() => {var}.data = String(({}) ?? \"\"));\n", self.value(expression)));
+                    js.push_str(&format!("{pad}{parent}.append({var});\n"));
+                }
+                // Branches and loops of a row go in a range of their own, kept
+                // up to date by their own effect like the ones of the page,
+                // the row around them is left as it is.
+                Node::If(group) => {
+                    *count += 1;
+                    let var = format!("e{}", count);
+                    js.push_str(&format!("{pad}let {var} = appendRange({parent});\n"));
+                    let shown = self.choose(group, |i| i.to_string(), "-1".to_string());
+                    js.push_str(&format!("{pad}showBranch({var}, () => {shown}, [\n"));
+                    for branch in &group.branches {
+                        js.push_str(&format!("{pad}    parent => {{\n"));
+                        self.build(&branch.children, "parent", item, count, indent + 2, js)?;
+                        js.push_str(&format!("{pad}    }},\n"));
+                    }
+                    js.push_str(&format!("{pad}]);\n"));
+                }
+                Node::For(each) => {
+                    *count += 1;
+                    let var = format!("e{}", count);
+                    js.push_str(&format!("{pad}let {var} = appendRange({parent});\n"));
+                    let each_item = each.item.as_str();
+                    let (items, key, by_index) = self.list_arguments(each);
+                    js.push_str(&format!(
+                        "{pad}forList({var}, () => {items}, ({each_item}, i) => {key}, (parent, {each_item}, i) => {{\n"
+                    ));
+                    self.build(&each.children, "parent", each_item, count, indent + 1, js)?;
+                    js.push_str(&format!("{pad}}}, {by_index});\n"));
+                }
+                Node::Style(raw) => {
+                    *count += 1;
+                    let var = format!("e{}", count);
+                    js.push_str(&format!("{pad}let {var} = document.createElement(\"style\");\n"));
+                    js.push_str(&format!("{pad}{var}.textContent = {};\n", js_string(&raw.content)));
+                    js.push_str(&format!("{pad}{parent}.append({var});\n"));
+                }
+                Node::Comment(_) | Node::Script(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// JavaScript for an attribute of the element in `var` that isn't written
+    /// in the page as it is, an event handler, a binding, a `class:name`
+    /// toggle or a value with `{...}` in it, which an effect keeps up to date.
+    fn dynamic_attribute(
+        &self,
+        var: &str,
+        element: &Element,
+        attribute: &Attribute,
+        item: Option<&str>,
+        pad: &str,
+    ) -> Result<String, Error> {
+        let name = attribute.name.as_str();
+        if name.starts_with("on:") {
+            return self.listener(var, attribute, item, pad);
+        }
+        if name.starts_with("bind:") {
+            return self.binding(var, element, attribute, pad);
+        }
+
+        let update = if let Some(class) = name.strip_prefix("class:") {
+            let condition = match attribute.value.as_deref() {
+                Some([Piece::Expression(condition)]) if !class.is_empty() => condition,
+                _ => {
+                    let message = format!("`{}` takes a condition in braces, like `class:active={{selected}}`", name);
+                    return error(attribute.start, message);
+                }
+            };
+            format!("{var}.classList.toggle({}, !!({}))", js_string(class), self.value(condition))
+        } else {
+            match attribute.value.as_deref().unwrap_or_default() {
+                // A value on its own can take the attribute away, like `disabled={busy}`.
+                [Piece::Expression(value)] => format!(
+                    "{{
+{pad}    let value = {};
+{pad}    if (value === false || value == null) {var}.removeAttribute({name});
+{pad}    else {var}.setAttribute({name}, value === true ? \"\" : value);
+{pad}}}",
+                    self.value(value),
+                    name = js_string(name)
+                ),
+                pieces => format!("{var}.setAttribute({}, {})", js_string(name), self.attribute_value(pieces)),
+            }
+        };
+
+        Ok(format!("{pad}effect(() => {update});\n"))
+    }
+
+    /// An `addEventListener` call for `on:event|modifiers={handler}` on the
+    /// element in `var`. A handler given by name is called with the event, or
+    /// with the item and the event inside a `<for>`, a function written in
+    /// place is called with the event and anything else is run as a statement.
+    fn listener(&self, var: &str, attribute: &Attribute, item: Option<&str>, pad: &str) -> Result<String, Error> {
+        let mut modifiers = attribute.name["on:".len()..].split('|');
+        let event = modifiers.next().unwrap_or_default();
+        if event.is_empty() {
+            return error(attribute.start, format!("`{}` needs an event name, like `on:click`", attribute.name));
+        }
+        let handler = match attribute.value.as_deref() {
+            Some([Piece::Expression(handler)]) if !handler.code.is_empty() => handler,
+            _ => {
+                let example = format!("{}={{save}}", event_attribute(&attribute.name));
+                return error(attribute.start, format!("`{}` takes a handler in braces, like `{}`", attribute.name, example));
+            }
+        };
+
+        let mut body = String::new();
+        let mut options = Vec::new();
+        for modifier in modifiers {
+            match modifier {
+                "preventDefault" => body.push_str(&format!("{pad}    event.preventDefault();\n")),
+                "stopPropagation" => body.push_str(&format!("{pad}    event.stopPropagation();\n")),
+                "self" => body.push_str(&format!("{pad}    if (event.target !== event.currentTarget) return;\n")),
+                "once" | "capture" | "passive" => options.push(format!("{}: true", modifier)),
+                _ => {
+                    return error(
+                        attribute.start,
+                        format!("unknown modifier `{}`, expected `preventDefault`, `stopPropagation`, `self`, `once`, `capture` or `passive`", modifier),
+                    )
+                }
+            }
+        }
+
+        let call = match handler_kind(&handler.code) {
+            Handler::Reference => match item {
+                Some(item) => format!("{}({}, event)", handler.code, item),
+                None => format!("{}(event)", handler.code),
+            },
+            Handler::Function => format!("({})(event)", handler.code),
+            Handler::Statement => handler.code.clone(),
+        };
+        body.push_str(&format!("{pad}    {};\n", call));
+
+        let options = if options.is_empty() { String::new() } else { format!(", {{ {} }}", options.join(", ")) };
+        Ok(format!("{pad}{var}.addEventListener({}, (event) => {{\n{body}{pad}}}{options});\n", js_string(event)))
+    }
+
+    /// Keeps the element in `var` and a signal in step both ways, for
+    /// `bind:value`, `bind:checked` and `bind:group`. The signal can be a
+    /// field of the value of a signal, `bind:value={user.name}`.
+    fn binding(&self, var: &str, element: &Element, attribute: &Attribute, pad: &str) -> Result<String, Error> {
+        let target = match attribute.value.as_deref() {
+            Some([Piece::Expression(target)]) => target,
+            _ => {
+                let message = format!("`{}` takes a signal in braces, like `{}={{name}}`", attribute.name, attribute.name);
+                return error(attribute.start, message);
+            }
+        };
+        let tokens = js_tokens(&target.code);
+        let path = tokens
+            .iter()
+            .enumerate()
+            .all(|(i, token)| if i % 2 == 0 { is_identifier(token) } else { *token == "." });
+        let signal = tokens.first().filter(|signal| self.signals.iter().any(|s| s == *signal));
+        let Some(signal) = signal.filter(|_| path && tokens.len() % 2 == 1) else {
+            let message = format!("`{}` needs a signal of the `<script>`, like `let name = new Signal(\"\")`", attribute.name);
+            return error(target.start, message);
+        };
+        let fields: Vec<String> = tokens.iter().skip(2).step_by(2).map(|field| js_string(field)).collect();
+        let read = format!("{}.get(){}", signal, tokens[1..].concat());
+        let write = |value: &str| {
+            if fields.is_empty() {
+                format!("{}.set({})", signal, value)
+            } else {
+                format!("{}.setAt([{}], {})", signal, fields.join(", "), value)
+            }
+        };
+
+        let tag = element.name.to_ascii_lowercase();
+        let kind = static_attribute(&element.attributes, "type").unwrap_or_default().to_ascii_lowercase();
+        let multiple = element.attributes.iter().any(|attribute| attribute.name.eq_ignore_ascii_case("multiple"));
+        let (update, event, value) = match (attribute.name.as_str(), tag.as_str(), kind.as_str()) {
+            ("bind:value", "select", _) if multiple => (
+                format!("let value = {read} ?? [];\n{pad}        Array.from(el.options).forEach(option => option.selected = value.includes(option.value));"),
+                "change",
+                write("Array.from(el.selectedOptions, option => option.value)"),
+            ),
+            ("bind:value", "input" | "textarea" | "select", _) => {
+                let value = if matches!(kind.as_str(), "number" | "range") {
+                    "el.value === \"\" ? null : Number(el.value)"
+                } else {
+                    "el.value"
+                };
+                (
+                    format!("let value = {read} ?? \"\";\n{pad}        if (el.value !== String(value)) el.value = value;"),
+                    if tag == "select" { "change" } else { "input" },
+                    write(value),
+                )
+            }
+            ("bind:checked", "input", "checkbox" | "radio") => {
+                (format!("el.checked = !!{read};"), "change", write("el.checked"))
+            }
+            ("bind:group", "input", "radio") => (
+                format!("el.checked = {read} === el.value;"),
+                "change",
+                format!("if (el.checked) {}", write("el.value")),
+            ),
+            ("bind:group", "input", "checkbox") => (
+                format!("el.checked = ({read} ?? []).includes(el.value);"),
+                "change",
+                write(&format!("[...({read} ?? []).filter(value => value !== el.value), ...(el.checked ? [el.value] : [])]")),
+            ),
+            ("bind:value" | "bind:checked" | "bind:group", _, _) => {
+                let expected = match attribute.name.as_str() {
+                    "bind:value" => "an `<input>`, `<textarea>` or `<select>`",
+                    "bind:checked" => "an `<input type=\"checkbox\">` or `<input type=\"radio\">`",
+                    _ => "an `<input type=\"radio\">` or `<input type=\"checkbox\">`",
+                };
+                return error(attribute.start, format!("`{}` can only be used on {}", attribute.name, expected));
+            }
+            _ => {
+                return error(
+                    attribute.start,
+                    format!("unknown binding `{}`, expected `bind:value`, `bind:checked` or `bind:group`", attribute.name),
+                )
+            }
+        };
+
+        let event = js_string(event);
+        // `renderList` sets the value of a `<select>` again once the options
+        // of a `<for>` in it changed.
+        let apply = if tag == "select" {
+            format!("el.ubiValue = () => {{\n{pad}        {update}\n{pad}    }};\n{pad}    effect(el.ubiValue);")
+        } else {
+            format!("effect(() => {{\n{pad}        {update}\n{pad}    }});")
+        };
+        Ok(format!(
+            "{pad}{{
+{pad}    let el = {var};
+{pad}    {apply}
+{pad}    el.addEventListener({event}, () => {{
+{pad}        {value};
+{pad}    }});
+{pad}}}
+"
+        ))
+    }
+
+    /// The value of an attribute as a JavaScript string.
+    fn attribute_value(&self, pieces: &[Piece]) -> String {
+        match pieces {
+            [Piece::Text(text)] => js_string(text),
+            _ => {
+                let mut hasil = String::from("`");
+                for piece in pieces {
+                    match piece {
+                        Piece::Text(text) => hasil.push_str(&template_text(text)),
+                        Piece::Expression(expression) => hasil.push_str(&format!("${{{}}}", self.value(expression))),
+                    }
+                }
+                hasil.push('`');
+                hasil
+            }
+        }
+    }
+}
+
+/// `on:submit` for `on:submit|preventDefault`.
+fn event_attribute(name: &str) -> &str {
+    name.split('|').next().unwrap_or(name)
+}
+
+/// Attributes set by the JavaScript of the component instead of being
+/// written in the page, `on:`, `bind:` and `class:` ones and values with
+/// `{...}` in them.
+fn is_dynamic(attribute: &Attribute) -> bool {
+    ["on:", "bind:", "class:"].iter().any(|prefix| attribute.name.starts_with(prefix))
+        || attribute.value.iter().flatten().any(|piece| matches!(piece, Piece::Expression(_)))
+}
+
+/// The value of the attribute `name`, when it is plain text.
+fn static_attribute(attributes: &[Attribute], name: &str) -> Option<String> {
+    attributes.iter().find_map(|attribute| match attribute.value.as_deref() {
+        Some([Piece::Text(value)]) if attribute.name.eq_ignore_ascii_case(name) => Some(value.clone()),
+        _ => None,
+    })
+}
+
+/// The `id` the element is written with, when it is plain text.
+fn static_id(attributes: &[Attribute]) -> Option<String> {
+    static_attribute(attributes, "id")
+}
+
+enum Handler {
+    /// `save` or `form.save`, a function to call.
+    Reference,
+    /// `(event) => ...` or `function (event) { ... }`.
+    Function,
+    /// Anything else, like `count.set(0)`.
+    Statement,
+}
+
+fn handler_kind(code: &str) -> Handler {
+    let tokens = js_tokens(code);
+    let reference = tokens
+        .iter()
+        .enumerate()
+        .all(|(i, token)| if i % 2 == 0 { is_identifier(token) } else { *token == "." });
+    if reference && tokens.len() % 2 == 1 {
+        return Handler::Reference;
+    }
+
+    // An arrow that isn't nested in brackets makes the whole handler a function.
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth -= 1,
+            "=" if depth == 0 && tokens.get(i + 1) == Some(&">") => return Handler::Function,
+            _ => {}
+        }
+    }
+    if matches!(tokens.first(), Some(&"function") | Some(&"async")) {
+        Handler::Function
+    } else {
+        Handler::Statement
+    }
+}
+
+/// A JavaScript string literal holding `text`, safe inside a `<script>`.
+fn js_string(text: &str) -> String {
+    serde_json::to_string(text).unwrap().replace("</", "<\\/")
+}
+
+/// Whether an `<a>` links to a page of the app, which `handleNavigation`
+/// loads without leaving the page.
+fn is_local_link(attributes: &[Attribute]) -> bool {
+    attributes.iter().any(|attribute| {
+        attribute.name.eq_ignore_ascii_case("href")
+            && matches!(attribute.value.as_deref(), Some([Piece::Text(href), ..]) if href.trim().starts_with('/'))
+    })
+}
+
+/// Whether the expressions of `nodes` read `i`, the index of the item.
+fn uses_index(nodes: &[Node]) -> bool {
+    let reads = |expression: &Expression| js_tokens(&expression.code).contains(&"i");
+    nodes.iter().any(|node| match node {
+        Node::Element(element) => {
+            element.attributes.iter().any(|attribute| {
+                attribute.value.iter().flatten().any(|piece| matches!(piece, Piece::Expression(e) if reads(e)))
+            }) || uses_index(&element.children)
+        }
+        Node::Interpolation(expression) => reads(expression),
+        Node::If(group) => group
+            .branches
+            .iter()
+            .any(|branch| branch.condition.as_ref().is_some_and(reads) || uses_index(&branch.children)),
+        Node::For(each) => {
+            let (ForSource::Each(source) | ForSource::Range(source)) = &each.source;
+            reads(source) || uses_index(&each.children)
+        }
+        _ => false,
+    })
+}
+
+/// Names of the signals a script declares, `let count = new Signal(0)`.
+pub fn signals(script: &str) -> Vec<String> {
+    let words = js_tokens(script);
+    let mut names = Vec::new();
+
+    for window in words.windows(5) {
+        if matches!(window[0], "let" | "const" | "var")
+            && is_identifier(window[1])
+            && window[2] == "="
+            && window[3] == "new"
+            && window[4] == "Signal"
+            && !names.iter().any(|name| name == window[1])
+        {
+            names.push(window[1].to_string());
+        }
+    }
+
+    names
+}
+
+/// Identifiers, literals and punctuation of a script, comments left out.
+/// Operators come out one character at a time.
+fn js_tokens(script: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut chars = script.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+
+        match c {
+            c if c.is_whitespace() => continue,
+            '/' if matches!(chars.peek(), Some((_, '/'))) => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                continue;
+            }
+            '/' if matches!(chars.peek(), Some((_, '*'))) => {
+                chars.next();
+                let mut previous = ' ';
+                for (_, c) in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                continue;
+            }
+            '"' | '\'' | '`' => {
+                let mut escaped = false;
+                for (i, next) in chars.by_ref() {
+                    end = i + next.len_utf8();
+                    if escaped {
+                        escaped = false;
+                    } else if next == '\\' {
+                        escaped = true;
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            c if is_identifier_char(c) => {
+                while let Some((i, next)) = chars.next_if(|(_, c)| is_identifier_char(*c)) {
+                    end = i + next.len_utf8();
+                }
+            }
+            _ => {}
+        }
+
+        tokens.push(&script[start..end]);
+    }
+
+    tokens
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+fn is_identifier(word: &str) -> bool {
+    word.chars().next().is_some_and(|c| !c.is_ascii_digit()) && word.chars().all(is_identifier_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(source: &str) -> String {
+        let Ok(component) = parse(source) else { panic!("{} doesn't parse", source) };
+        match component.nodes.first() {
+            Some(Node::If(group)) => group.branches[0].condition.as_ref().unwrap().code.clone(),
+            _ => panic!("{} is not an <if>", source),
+        }
+    }
+
+    fn parse_error(source: &str) -> Error {
+        match parse(source) {
+            Ok(_) => panic!("{} parses", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn comparison_in_brackets_stays_in_the_condition() {
+        assert_eq!(condition("<if {count.get() > 1}><p>x</p></if>"), "count.get() > 1");
+        assert_eq!(condition("<if (count.get() > 1)><p>x</p></if>"), "(count.get() > 1)");
+        assert_eq!(condition("<if ok>a -> b</if>"), "ok");
+    }
+
+    #[test]
+    fn bare_comparison_is_an_error_at_its_operator() {
+        let source = "<if count.get() > 1><p>x</p></if>";
+        let e = parse_error(source);
+        assert_eq!(e.offset, source.find(" > ").unwrap() + 1);
+        assert!(e.message.contains("parentheses or braces"), "{}", e.message);
+
+        let source = "<if a>x</if><else-if count.get() >= 1>y</else-if>";
+        assert_eq!(parse_error(source).offset, source.find(">=").unwrap());
+        assert!(compile("routes/ui.ubi", "<if n > 1>1</if>").unwrap_err().contains("1:7"));
+    }
+
+    #[test]
+    fn branches_and_loops_of_a_row_have_their_own_effect() {
+        let Ok(page) = compile(
+            "routes/ui.ubi",
+            "<for user in users><li><if {admin.get()}>admin</if><for tag in user.tags>{tag}</for></li></for>",
+        ) else {
+            panic!("the component doesn't compile")
+        };
+        assert!(page.contains("showBranch(e2, () => (admin.get()) ? 0 : -1, ["), "{}", page);
+        assert!(page.contains("let e2 = appendRange(e1);\n"), "{}", page);
+        assert!(page.contains("let e3 = appendRange(e1);\n"), "{}", page);
+        assert!(page.contains("forList(e3, () => user.tags, (tag, i) => i, (parent, tag, i) => {"), "{}", page);
+        assert!(!page.contains("if (admin.get())"), "{}", page);
+    }
+
+    #[test]
+    fn loops_of_the_page_are_between_comments() {
+        let Ok(page) = compile(
+            "routes/ui.ubi",
+            "<table><tbody><for user in users><tr><td>{user.name}</td></tr></for></tbody></table>",
+        ) else {
+            panic!("the component doesn't compile")
+        };
+        let start = page.find("<tbody><!--ubi:").unwrap() + "<tbody><!--ubi:".len();
+        let id = &page[start..start + page[start..].find("-->").unwrap()];
+        assert!(page.contains(&format!("<!--ubi:{id}--><!--/ubi:{id}--></tbody>")), "{}", page);
+        assert!(page.contains(&format!("let {id} = range(\"{id}\");")), "{}", page);
+        assert!(!page.contains("display: contents"), "{}", page);
+    }
+
+    #[test]
+    fn select_is_bound_after_its_options_are_built() {
+        let script = "<script>let choice = new Signal(\"b\"); let options = new Signal([\"a\", \"b\"]);</script>";
+        let select = "<select bind:value={choice}><for o in options.get()><option value={o}>{o}</option></for></select>";
+        let Ok(page) = compile("routes/ui.ubi", &format!("{script}{select}<for x in options.get()>{select}</for>")) else {
+            panic!("the component doesn't compile")
+        };
+        let first = page.find("forList(").unwrap();
+        assert!(first < page.find("el.ubiValue = () => {").unwrap(), "{}", page);
+        // In a row, the nested list of options comes before the binding too.
+        let row = &page[page.find("function row_").unwrap()..];
+        assert!(row.find("forList(").unwrap() < row.find("el.ubiValue = () => {").unwrap(), "{}", page);
+        assert!(page.contains("effect(el.ubiValue);"), "{}", page);
+    }
+
+    #[test]
+    fn branches_of_the_page_are_between_comments() {
+        let Ok(page) = compile("routes/ui.ubi", "<p>a <if {ok.get()}><b>b</b></if><else>c</else> d</p>") else {
+            panic!("the component doesn't compile")
+        };
+        let start = page.find("<p>a <!--ubi:").unwrap() + "<p>a <!--ubi:".len();
+        let id = &page[start..start + page[start..].find("_0-->").unwrap()];
+        assert!(
+            page.contains(&format!("<!--ubi:{id}_0--><b>b</b><!--/ubi:{id}_0--><!--ubi:{id}_1-->c<!--/ubi:{id}_1--> d</p>")),
+            "{}",
+            page
+        );
+        assert!(
+            page.contains(&format!("ifGroup([range(\"{id}_0\"), range(\"{id}_1\")], () => (ok.get()) ? 0 : 1);")),
+            "{}",
+            page
+        );
+        assert!(!page.contains("style.display"), "{}", page);
+    }
+}